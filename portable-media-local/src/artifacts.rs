@@ -0,0 +1,105 @@
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::fs;
+
+use crate::hash;
+
+/// Numbers temporary files, so puts running at the same time never share one
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// On disk cache of files derived from library files (subtitle conversions for now),
+/// keyed by the SHA-256 of the source file's contents rather than its path
+/// Renaming or moving a file keeps its artifacts, and identical files share them
+/// Artifacts live at `<dir>/<first 2 hex digits>/<hex hash>.<kind>`
+pub struct ArtifactStore {
+    dir: String,
+}
+
+impl ArtifactStore {
+    pub fn new(dir: &str) -> ArtifactStore {
+        ArtifactStore {
+            dir: dir.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn path_for(&self, source_hash: &[u8; 32], kind: &str) -> String {
+        let hex = hash::to_hex(source_hash);
+        format!("{}/{}/{}.{}", self.dir, &hex[..2], hex, kind)
+    }
+
+    /// Returns the `kind` artifact for the source with `source_hash`, if it's been stored
+    pub async fn get(&self, source_hash: &[u8; 32], kind: &str) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(self.path_for(source_hash, kind)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stores the `kind` artifact for the source with `source_hash`
+    /// Written to a temporary file first, so a crash never leaves a half written artifact
+    pub async fn put(&self, source_hash: &[u8; 32], kind: &str, data: &[u8]) -> Result<(), Error> {
+        let path = self.path_for(source_hash, kind);
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            fs::create_dir_all(parent).await?;
+        }
+        let tmp = format!(
+            "{}.tmp{}-{}",
+            path,
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        );
+        fs::write(&tmp, data).await?;
+        fs::rename(&tmp, &path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[tokio::test]
+    async fn test_put_get() {
+        let dir = TempDir::new("artifacts");
+        let store = ArtifactStore::new(dir.join("store").to_str().unwrap());
+        let source = [0xABu8; 32];
+
+        assert_eq!(store.get(&source, "vtt").await.unwrap(), None);
+        store.put(&source, "vtt", b"WEBVTT\n").await.unwrap();
+        assert_eq!(
+            store.get(&source, "vtt").await.unwrap().unwrap(),
+            b"WEBVTT\n"
+        );
+        // Different kinds of artifact for the same source don't collide
+        assert_eq!(store.get(&source, "thumb").await.unwrap(), None);
+        assert!(dir
+            .join("store/ab")
+            .join(format!("{}.vtt", "ab".repeat(32)))
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_put() {
+        let dir = TempDir::new("artifacts-concurrent");
+        let store = std::sync::Arc::new(ArtifactStore::new(dir.as_str()));
+        let source = [0xCDu8; 32];
+        // Identical files converted at the same time both store the same artifact
+        let puts: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                tokio::spawn(async move { store.put(&source, "vtt", b"WEBVTT\n").await })
+            })
+            .collect();
+        for put in puts {
+            put.await.unwrap().unwrap();
+        }
+        assert_eq!(
+            store.get(&source, "vtt").await.unwrap().unwrap(),
+            b"WEBVTT\n"
+        );
+        let stored: Vec<_> = std::fs::read_dir(dir.join("cd")).unwrap().collect();
+        assert_eq!(stored.len(), 1);
+    }
+}
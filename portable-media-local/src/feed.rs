@@ -0,0 +1,329 @@
+/// Escapes the five XML special characters
+pub fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encodes a relative path for use in a URL, leaving the `/` separators alone
+pub fn url_encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Returns the MIME type for a file based on its extension, if it's one we serve
+pub fn mime_type(name: &str) -> Option<&'static str> {
+    let ext = name.rsplit_once('.')?.1.to_lowercase();
+    let mime = match ext.as_str() {
+        "mp3" => "audio/mpeg",
+        "m4a" | "m4b" => "audio/mp4",
+        "aac" => "audio/aac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "flac" => "audio/flac",
+        "wav" => "audio/wav",
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "srt" => "application/x-subrip",
+        "vtt" => "text/vtt",
+        "txt" => "text/plain",
+        "epub" => "application/epub+zip",
+        "pdf" => "application/pdf",
+        "cbz" => "application/vnd.comicbook+zip",
+        "cbr" => "application/vnd.comicbook-rar",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+/// Splits seconds since the unix epoch into (year, month, day, hour, minute, second, weekday)
+/// with weekday 0 being Sunday. Uses the days-to-civil algorithm from Howard Hinnant
+pub fn civil_from_unix(secs: u64) -> (u64, u64, u64, u64, u64, u64, u64) {
+    let days = secs / 86400;
+    let rem = secs % 86400;
+    let weekday = (days + 4) % 7;
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60,
+        weekday,
+    )
+}
+
+/// Formats a unix timestamp as an RFC 822 date, as RSS wants for `pubDate`
+pub fn rfc822_date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (y, mo, d, h, mi, s, wd) = civil_from_unix(secs);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[wd as usize],
+        d,
+        MONTHS[(mo - 1) as usize],
+        y,
+        h,
+        mi,
+        s
+    )
+}
+
+/// Formats a unix timestamp as an RFC 3339 date, as Atom wants for `updated`
+pub fn rfc3339_date(secs: u64) -> String {
+    let (y, mo, d, h, mi, s, _) = civil_from_unix(secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, mo, d, h, mi, s)
+}
+
+/// A single podcast episode, pointing at a file the server hands out
+pub struct Episode {
+    pub title: String,
+    pub author: Option<String>,
+    pub episode: Option<u32>,
+    pub url: String,
+    pub length: u64,
+    pub mime: &'static str,
+    pub published: u64,
+}
+
+/// Renders a podcast RSS 2.0 feed with the iTunes namespace most podcast apps expect
+pub fn podcast_rss(title: &str, link: &str, episodes: &[Episode]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <rss version=\"2.0\" xmlns:itunes=\"http://www.itunes.com/dtds/podcast-1.0.dtd\">\n\
+         <channel>\n",
+    );
+    out.push_str(&format!("<title>{}</title>\n", xml_escape(title)));
+    out.push_str(&format!("<link>{}</link>\n", xml_escape(link)));
+    out.push_str(&format!(
+        "<description>{}</description>\n",
+        xml_escape(title)
+    ));
+    if let Some(latest) = episodes.iter().map(|e| e.published).max() {
+        out.push_str(&format!(
+            "<lastBuildDate>{}</lastBuildDate>\n",
+            rfc822_date(latest)
+        ));
+    }
+    for e in episodes {
+        out.push_str("<item>\n");
+        out.push_str(&format!("<title>{}</title>\n", xml_escape(&e.title)));
+        if let Some(author) = &e.author {
+            out.push_str(&format!(
+                "<itunes:author>{}</itunes:author>\n",
+                xml_escape(author)
+            ));
+        }
+        if let Some(n) = e.episode {
+            out.push_str(&format!("<itunes:episode>{}</itunes:episode>\n", n));
+        }
+        out.push_str(&format!(
+            "<enclosure url=\"{}\" length=\"{}\" type=\"{}\"/>\n",
+            xml_escape(&e.url),
+            e.length,
+            e.mime
+        ));
+        out.push_str(&format!(
+            "<guid isPermaLink=\"false\">{}</guid>\n",
+            xml_escape(&e.url)
+        ));
+        out.push_str(&format!(
+            "<pubDate>{}</pubDate>\n",
+            rfc822_date(e.published)
+        ));
+        out.push_str("</item>\n");
+    }
+    out.push_str("</channel>\n</rss>\n");
+    out
+}
+
+/// An entry in an OPDS catalog, either a subsection to browse into or a book to download
+pub enum OpdsEntry {
+    Navigation {
+        title: String,
+        url: String,
+        updated: u64,
+    },
+    Acquisition {
+        title: String,
+        url: String,
+        mime: &'static str,
+        length: u64,
+        updated: u64,
+    },
+}
+
+const OPDS_CATALOG_TYPE: &str = "application/atom+xml;profile=opds-catalog";
+
+/// Types listed as downloads in OPDS catalogs: EPUB, PDF, and CBZ/CBR comic archives
+pub const BOOK_MIME_TYPES: [&str; 4] = [
+    "application/epub+zip",
+    "application/pdf",
+    "application/vnd.comicbook+zip",
+    "application/vnd.comicbook-rar",
+];
+
+/// Renders an OPDS 1.2 catalog (an Atom feed) for a single directory
+/// `url` is the catalog's own URL and doubles as its Atom id
+pub fn opds_catalog(title: &str, url: &str, entries: &[OpdsEntry]) -> String {
+    let updated = entries
+        .iter()
+        .map(|e| match e {
+            OpdsEntry::Navigation { updated, .. } | OpdsEntry::Acquisition { updated, .. } => {
+                *updated
+            }
+        })
+        .max()
+        .unwrap_or(0);
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/terms/\">\n",
+    );
+    out.push_str(&format!("<id>{}</id>\n", xml_escape(url)));
+    out.push_str(&format!("<title>{}</title>\n", xml_escape(title)));
+    out.push_str(&format!("<updated>{}</updated>\n", rfc3339_date(updated)));
+    out.push_str(&format!(
+        "<link rel=\"self\" href=\"{}\" type=\"{}\"/>\n",
+        xml_escape(url),
+        OPDS_CATALOG_TYPE
+    ));
+    for e in entries {
+        out.push_str("<entry>\n");
+        match e {
+            OpdsEntry::Navigation {
+                title,
+                url,
+                updated,
+            } => {
+                out.push_str(&format!("<title>{}</title>\n", xml_escape(title)));
+                out.push_str(&format!("<id>{}</id>\n", xml_escape(url)));
+                out.push_str(&format!("<updated>{}</updated>\n", rfc3339_date(*updated)));
+                out.push_str(&format!(
+                    "<link rel=\"subsection\" href=\"{}\" type=\"{}\"/>\n",
+                    xml_escape(url),
+                    OPDS_CATALOG_TYPE
+                ));
+            }
+            OpdsEntry::Acquisition {
+                title,
+                url,
+                mime,
+                length,
+                updated,
+            } => {
+                out.push_str(&format!("<title>{}</title>\n", xml_escape(title)));
+                out.push_str(&format!("<id>{}</id>\n", xml_escape(url)));
+                out.push_str(&format!("<updated>{}</updated>\n", rfc3339_date(*updated)));
+                out.push_str(&format!("<dc:extent>{} bytes</dc:extent>\n", length));
+                out.push_str(&format!(
+                    "<link rel=\"http://opds-spec.org/acquisition\" href=\"{}\" type=\"{}\" length=\"{}\"/>\n",
+                    xml_escape(url),
+                    mime,
+                    length
+                ));
+            }
+        }
+        out.push_str("</entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(rfc822_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(rfc822_date(1709210096), "Thu, 29 Feb 2024 12:34:56 GMT");
+        assert_eq!(rfc3339_date(1709210096), "2024-02-29T12:34:56Z");
+    }
+
+    #[test]
+    fn test_escaping() {
+        assert_eq!(xml_escape("a<b & \"c\""), "a&lt;b &amp; &quot;c&quot;");
+        assert_eq!(url_encode_path("My Show/ep 1.mp3"), "My%20Show/ep%201.mp3");
+    }
+
+    #[test]
+    fn test_podcast_rss() {
+        let feed = podcast_rss(
+            "Show",
+            "http://host/",
+            &[Episode {
+                title: "One & Two".to_string(),
+                author: None,
+                episode: Some(2),
+                url: "http://host/files/a.mp3".to_string(),
+                length: 42,
+                mime: "audio/mpeg",
+                published: 0,
+            }],
+        );
+        assert!(feed.contains("<title>One &amp; Two</title>"));
+        assert!(feed.contains(
+            "<enclosure url=\"http://host/files/a.mp3\" length=\"42\" type=\"audio/mpeg\"/>"
+        ));
+        assert!(feed.ends_with("</channel>\n</rss>\n"));
+    }
+
+    #[test]
+    fn test_opds_catalog() {
+        let catalog = opds_catalog(
+            "Books",
+            "http://host/opds/Books",
+            &[
+                OpdsEntry::Navigation {
+                    title: "Comics".to_string(),
+                    url: "http://host/opds/Books/Comics".to_string(),
+                    updated: 0,
+                },
+                OpdsEntry::Acquisition {
+                    title: "Dune".to_string(),
+                    url: "http://host/files/Books/Dune.epub".to_string(),
+                    mime: "application/epub+zip",
+                    length: 10,
+                    updated: 1709210096,
+                },
+            ],
+        );
+        assert!(catalog.contains("<updated>2024-02-29T12:34:56Z</updated>\n<link rel=\"self\""));
+        assert!(catalog.contains("rel=\"subsection\" href=\"http://host/opds/Books/Comics\""));
+        assert!(catalog.contains(
+            "href=\"http://host/files/Books/Dune.epub\" type=\"application/epub+zip\" length=\"10\""
+        ));
+    }
+}
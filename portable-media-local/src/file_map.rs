@@ -13,14 +13,23 @@ use tokio::{
 type DirMap = TokioLock<HashMap<String, Arc<FileNode>>>;
 
 use lru::LruCache;
-use tokio::io::AsyncReadExt;
 use async_recursion::async_recursion;
 
+use crate::artifacts::ArtifactStore;
+use crate::feed;
+use crate::hash::{self, Algorithm};
+use crate::grouping::{self, GroupRule, MediaGroup};
 use crate::log::{self, log_err};
+use crate::reader;
+use crate::subtitle;
+use crate::tags;
+use crate::zip::{ZipEntry, ZipStream};
 
 pub struct FileNode {
     pub name: String,
     pub size: u64,
+    /// Last modification time, in seconds since the unix epoch
+    pub modified: u64,
     pub children: Option<DirMap>,
 }
 
@@ -31,6 +40,7 @@ impl FileNode {
         let metadata = file.metadata()?;
 
         let mut size: u64 = 0;
+        let modified = metadata.mtime().max(0) as u64;
         let name = match path.split("/").last() {
             Some(s) => s.to_string(),
             None => {
@@ -97,15 +107,35 @@ impl FileNode {
         return Ok(FileNode {
             name,
             size,
+            modified,
             children,
         });
     }
 }
 
+/// Joins two relative paths in the map, either of which may be empty
+fn join_path(a: &str, b: &str) -> String {
+    match (a.is_empty(), b.is_empty()) {
+        (true, _) => b.to_string(),
+        (_, true) => a.to_string(),
+        _ => format!("{}/{}", a, b),
+    }
+}
+
+/// A file's hash, along with the size and modification time it was taken at
+type HashEntry = ([u8; 32], u64, u64);
+
 pub struct FileMap {
     FULL_ROOT_PATH: String,
     head: Arc<FileNode>,
     lru: Arc<Mutex<LruCache<String, Arc<Vec<u8>>>>>,
+    /// Files derived from library files, keyed by "<kind>:<hex SHA-256 of the source>"
+    derived: Arc<Mutex<LruCache<String, Arc<Vec<u8>>>>>,
+    /// Where derived files are kept on disk between runs, if anywhere
+    artifacts: Option<ArtifactStore>,
+    /// Hashes of files by path and algorithm
+    hashes: Arc<Mutex<HashMap<(String, Algorithm), HashEntry>>>,
+    read_chunk_size: usize,
 }
 
 impl FileMap {
@@ -126,17 +156,40 @@ impl FileMap {
             FULL_ROOT_PATH: root_dir.to_string(),
             head,
             lru: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(20).unwrap()))),
+            derived: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(20).unwrap()))),
+            artifacts: None,
+            hashes: Arc::new(Mutex::new(HashMap::new())),
+            read_chunk_size: reader::DEFAULT_CHUNK_SIZE,
         })
     }
 
+    /// Sets how many bytes are read from disk at a time when loading, hashing, and
+    /// streaming files. Rounded up to a multiple of 4 KiB
+    /// Bigger reads help a lot on SD cards and network filesystems
+    pub fn with_read_chunk_size(mut self, size: usize) -> FileMap {
+        self.read_chunk_size = reader::aligned_chunk_size(size);
+        self
+    }
+
+    /// Keeps derived files (like subtitles converted to WebVTT) in `dir` on disk,
+    /// so they survive restarts. `dir` should be outside the library
+    pub fn with_artifact_dir(mut self, dir: &str) -> FileMap {
+        self.artifacts = Some(ArtifactStore::new(dir));
+        self
+    }
+
+    pub fn read_chunk_size(&self) -> usize {
+        self.read_chunk_size
+    }
+
 
     /// Returns a reference to the file node in the map for the given path
     /// Returns an `Arc<FileNode>` if the file is found, otherwise returns an `io::Error`
     /// Passing "" to this function will return a reference to the root node
     async fn get_file_ref(&self, path: &str) -> Result<Arc<FileNode>, io::Error> {
-        let path_split: Vec<&str> = path.split('/').collect();
+        let path_split: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
         let mut current_node: Arc<FileNode> = self.head.clone();
-        if path_split.len() > 1 {
+        if !path_split.is_empty() {
             for i in 0..path_split.len() {
                 let current_node_clone = current_node.clone();
                 if let Some(ref children) = current_node_clone.children {
@@ -169,17 +222,15 @@ impl FileMap {
     /// Remember when using not to add the 'root' directory to the path
     /// (e.g. if the root directory is "test_dir", use "testfile1.txt" as the path)
     async fn find_file_in_map(&self, path: &str) -> Result<Arc<Vec<u8>>, io::Error> {
-        // Start opening file, allows for efficient tasking.
-        let file = tokio::fs::File::open(format!("{}/{}", self.FULL_ROOT_PATH, path));
-        
         // Check if the file exists in the map
-        let r = self.get_file_ref(path).await?;
-
-        let mut buf: Vec<u8> = Vec::with_capacity(r.size as usize);
+        self.get_file_ref(path).await?;
 
-        let mut finished_file = file.await?;
-
-        finished_file.read_to_end(&mut buf).await?;
+        // Read in chunk sized pieces, straight into a buffer sized for the file
+        let buf = reader::read_to_vec(
+            format!("{}/{}", self.FULL_ROOT_PATH, path),
+            self.read_chunk_size,
+        )
+        .await?;
 
         return Ok(Arc::new(buf));
     }
@@ -209,13 +260,517 @@ impl FileMap {
         }
     }
 
-    
-    
+    /// Lists the entries of the directory at `path`, sorted by name
+    /// Passing "" lists the root directory
+    pub async fn list_dir(&self, path: &str) -> Result<Vec<Arc<FileNode>>, io::Error> {
+        let dir = self.get_file_ref(path).await?;
+        let children = match dir.children {
+            Some(ref c) => c,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("Error: {} is not a directory, cannot list", path),
+                ))
+            }
+        };
+        let mut entries: Vec<Arc<FileNode>> = children.read().await.values().cloned().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Returns where the file at `path` in the map lives on disk
+    pub fn disk_path(&self, path: &str) -> String {
+        join_path(&self.FULL_ROOT_PATH, path.trim_matches('/'))
+    }
+
+    /// Returns whether there is a file or directory at `path` in the map
+    pub async fn contains(&self, path: &str) -> bool {
+        self.get_file_ref(path).await.is_ok()
+    }
+
+    /// Adds the file or directory at `path` to the map once it exists on disk,
+    /// along with any of its parent directories that the map doesn't know about yet
+    /// If something is already at `path` it's replaced
+    pub async fn add_path(&self, path: &str) -> Result<(), io::Error> {
+        let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let mut current = self.head.clone();
+        for (i, part) in parts.iter().enumerate() {
+            let next = {
+                let children = match current.children {
+                    Some(ref c) => c,
+                    None => {
+                        return Err(io::Error::new(
+                            ErrorKind::NotADirectory,
+                            format!("Error, file {} is not a directory, cannot add to it", part),
+                        ))
+                    }
+                };
+                let existing = children.read().await.get(*part).cloned();
+                match existing {
+                    Some(node) if i + 1 < parts.len() && node.children.is_some() => node,
+                    _ => {
+                        // Either the rest of the path is new or this is the file itself,
+                        // building from here on disk picks up everything below
+                        let added_path = parts[..=i].join("/");
+                        let node = FileNode::build_from_path(&self.disk_path(&added_path)).await?;
+                        children.write().await.insert(part.to_string(), Arc::new(node));
+                        self.forget_cached(&added_path);
+                        return Ok(());
+                    }
+                }
+            };
+            current = next;
+        }
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Error: cannot add the root directory to the map",
+        ))
+    }
+
+    /// Removes the file or directory at `path` from the map (not from disk),
+    /// dropping anything cached for it
+    pub async fn remove_path(&self, path: &str) -> Result<(), io::Error> {
+        let path = path.trim_matches('/');
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (parent, name),
+            None => ("", path),
+        };
+        let parent = self.get_file_ref(parent).await?;
+        let removed = match parent.children {
+            Some(ref c) => c.write().await.remove(name),
+            None => None,
+        };
+        if removed.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                "File not found in file map",
+            ));
+        }
+        self.forget_cached(path);
+        Ok(())
+    }
+
+    /// Renames the file or directory at `from` to `to` on disk and in the map, creating
+    /// any missing parent directories of `to`
+    /// The cached contents and hash of a renamed file carry over, since the file itself
+    /// hasn't changed. Returns an `io::Error` rather than overwrite anything already at `to`
+    pub async fn rename_path(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        let from = from.trim_matches('/');
+        let to = to.trim_matches('/');
+        self.get_file_ref(from).await?;
+        if self.contains(to).await || tokio::fs::try_exists(self.disk_path(to)).await? {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Error: cannot rename {} to {}, it already exists", from, to),
+            ));
+        }
+        if let Some((parent, _)) = to.rsplit_once('/') {
+            tokio::fs::create_dir_all(self.disk_path(parent)).await?;
+        }
+        tokio::fs::rename(self.disk_path(from), self.disk_path(to)).await?;
+
+        let cached = self.lru.lock().unwrap().peek(from).cloned();
+        let hashes: Vec<(Algorithm, HashEntry)> = {
+            let known = self.hashes.lock().unwrap();
+            Algorithm::ALL
+                .iter()
+                .filter_map(|&a| known.get(&(from.to_string(), a)).map(|&h| (a, h)))
+                .collect()
+        };
+        self.remove_path(from).await?;
+        self.add_path(to).await?;
+        if let Some(data) = cached {
+            self.lru.lock().unwrap().put(to.to_string(), data);
+        }
+        let mut known = self.hashes.lock().unwrap();
+        for (algorithm, hash) in hashes {
+            known.insert((to.to_string(), algorithm), hash);
+        }
+        Ok(())
+    }
+
+    /// Drops every cached file and hash for `path` and anything under it
+    /// Derived files are keyed by content rather than path, so they stay
+    fn forget_cached(&self, path: &str) {
+        let under = format!("{}/", path);
+        let matches = |p: &str| p == path || p.starts_with(&under);
+
+        let mut lru = self.lru.lock().unwrap();
+        let stale: Vec<String> = lru.iter().map(|(k, _)| k).filter(|k| matches(k)).cloned().collect();
+        for k in stale {
+            lru.pop(&k);
+        }
+        drop(lru);
+
+        self.hashes.lock().unwrap().retain(|(k, _), _| !matches(k));
+    }
+
+    /// Lists the directory at `path` with related files (RAW+JPEG pairs, a video and its
+    /// subtitles, etc.) grouped into single items according to `rules`
+    /// Paths in the returned groups are relative to the root, so they can be passed
+    /// straight to `get_file`. Subdirectories are listed as items of their own, and
+    /// everything comes back sorted by name
+    pub async fn list_grouped(
+        &self,
+        path: &str,
+        rules: &[GroupRule],
+    ) -> Result<Vec<MediaGroup>, io::Error> {
+        let dir = path.trim_matches('/');
+        let (dirs, files): (Vec<_>, Vec<_>) = self
+            .list_dir(dir)
+            .await?
+            .into_iter()
+            .partition(|node| node.children.is_some());
+        let names: Vec<String> = files.iter().map(|node| node.name.clone()).collect();
+        let to_path = |name: String| {
+            if dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", dir, name)
+            }
+        };
+        let mut groups: Vec<MediaGroup> = grouping::group_files(&names, rules)
+            .into_iter()
+            .map(|g| MediaGroup {
+                primary: to_path(g.primary),
+                companions: g.companions.into_iter().map(to_path).collect(),
+                is_dir: false,
+            })
+            .collect();
+        groups.extend(dirs.iter().map(|node| MediaGroup {
+            primary: to_path(node.name.clone()),
+            companions: Vec::new(),
+            is_dir: true,
+        }));
+        groups.sort_by(|a, b| a.primary.cmp(&b.primary));
+        Ok(groups)
+    }
+
+    /// Returns the group the file at `path` belongs to, whether it's the primary or a companion
+    pub async fn get_group(
+        &self,
+        path: &str,
+        rules: &[GroupRule],
+    ) -> Result<MediaGroup, io::Error> {
+        let path = path.trim_matches('/');
+        let dir = match path.rsplit_once('/') {
+            Some((dir, _)) => dir,
+            None => "",
+        };
+        self.list_grouped(dir, rules)
+            .await?
+            .into_iter()
+            .find(|g| g.primary == path || g.companions.iter().any(|c| c == path))
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "File not found in file map"))
+    }
+
+    /// Walks the directory at `path` depth first, returning every node under it (including
+    /// `path` itself as "") along with its path relative to `path`
+    /// Each directory's entries are listed in name order, files before subdirectories
+    pub async fn walk(&self, path: &str) -> Result<Vec<(String, Arc<FileNode>)>, io::Error> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![(String::new(), self.get_file_ref(path).await?)];
+        while let Some((rel_path, node)) = stack.pop() {
+            nodes.push((rel_path.clone(), node.clone()));
+            let children = match node.children {
+                Some(ref c) => c.read().await,
+                None => continue,
+            };
+            let mut names: Vec<&String> = children.keys().collect();
+            names.sort();
+            let mut subdirs = Vec::new();
+            for name in names {
+                let child = children[name].clone();
+                let child_path = join_path(&rel_path, name);
+                if child.children.is_some() {
+                    subdirs.push((child_path, child));
+                } else {
+                    nodes.push((child_path, child));
+                }
+            }
+            // Reversed so popping off the stack walks directories in name order
+            stack.extend(subdirs.into_iter().rev());
+        }
+        Ok(nodes)
+    }
+
+    /// Returns the `algorithm` hash of the file at `path`
+    /// Hashes are kept after the first time and reused until the file's size or
+    /// modification time in the map changes
+    pub async fn file_hash(&self, path: &str, algorithm: Algorithm) -> Result<[u8; 32], io::Error> {
+        let path = path.trim_matches('/');
+        let node = self.get_file_ref(path).await?;
+        if node.children.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::IsADirectory,
+                format!("Error: {} is a directory, cannot hash", path),
+            ));
+        }
+        let key = (path.to_string(), algorithm);
+        if let Some(&(hash, size, modified)) = self.hashes.lock().unwrap().get(&key) {
+            if size == node.size && modified == node.modified {
+                return Ok(hash);
+            }
+        }
+        let hash =
+            hash::hash_file(self.disk_path(path).as_str(), algorithm, self.read_chunk_size).await?;
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(key, (hash, node.size, node.modified));
+        Ok(hash)
+    }
+
+    /// Builds `SHA256SUMS` or `B3SUMS` manifests (the format `sha256sum -c` and `b3sum -c`
+    /// check) for the directory at `path`, returned as (manifest path, manifest contents) pairs
+    /// With `per_directory` every directory gets its own manifest listing just its files,
+    /// otherwise there is one manifest at `path` covering everything under it
+    /// Existing manifests of either kind are left out of the manifests
+    pub async fn checksum_manifests(
+        &self,
+        path: &str,
+        algorithm: Algorithm,
+        per_directory: bool,
+    ) -> Result<Vec<(String, String)>, io::Error> {
+        let dir = path.trim_matches('/');
+        // Manifests by the directory they sit in, relative to `dir`
+        let mut manifests: Vec<(String, String)> = vec![(String::new(), String::new())];
+        for (rel_path, node) in self.walk(dir).await? {
+            if node.children.is_some() {
+                if per_directory && !rel_path.is_empty() {
+                    manifests.push((rel_path, String::new()));
+                }
+                continue;
+            }
+            if Algorithm::ALL.iter().any(|a| a.manifest_name() == node.name) {
+                continue;
+            }
+            let hash = self.file_hash(&join_path(dir, &rel_path), algorithm).await?;
+            // Walk lists a directory's files right after the directory itself,
+            // so the file always belongs in the last manifest
+            let (manifest_dir, contents) = manifests.last_mut().unwrap();
+            let listed_path = if per_directory {
+                rel_path[manifest_dir.len()..].trim_start_matches('/')
+            } else {
+                rel_path.as_str()
+            };
+            contents.push_str(&hash::manifest_line(&hash, listed_path));
+        }
+        Ok(manifests
+            .into_iter()
+            .map(|(manifest_dir, contents)| {
+                let manifest_path =
+                    join_path(&join_path(dir, &manifest_dir), algorithm.manifest_name());
+                (manifest_path, contents)
+            })
+            .collect())
+    }
+
+    /// Returns a `ZipStream` that produces a ZIP of the directory at `path` as it's read,
+    /// so a whole album can be downloaded without building the archive on disk first
+    /// Everything in the archive sits under a folder named after the directory
+    /// Anything that can't go in a ZIP is rejected here, before the first byte is sent
+    pub async fn zip_dir(&self, path: &str) -> Result<ZipStream, io::Error> {
+        let dir = path.trim_matches('/');
+        let node = self.get_file_ref(dir).await?;
+        if node.children.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::NotADirectory,
+                format!("Error: {} is not a directory, cannot zip", path),
+            ));
+        }
+
+        let mut entries = Vec::new();
+        for (rel_path, child) in self.walk(dir).await? {
+            let name = join_path(&node.name, &rel_path);
+            // The longest name a ZIP header has room for, with the directory's trailing `/`
+            if name.len() >= u16::MAX as usize {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Error: path {} is too long to put in a ZIP", name),
+                ));
+            }
+            if child.children.is_some() {
+                entries.push(ZipEntry {
+                    name: format!("{}/", name),
+                    disk_path: String::new(),
+                    size: 0,
+                    modified: child.modified,
+                });
+            } else {
+                entries.push(ZipEntry {
+                    name,
+                    disk_path: format!("{}/{}", self.FULL_ROOT_PATH, join_path(dir, &rel_path)),
+                    size: child.size,
+                    modified: child.modified,
+                });
+            }
+        }
+        Ok(ZipStream::new(entries).with_chunk_size(self.read_chunk_size))
+    }
+
+    /// Builds a podcast RSS feed out of the audio files directly inside `dir`
+    /// Episode titles and authors come from the files' tags when they have them,
+    /// and enclosure URLs are `base_url` followed by the file's path in the map
+    /// Newest files (by modification time) come first, like podcast apps expect
+    pub async fn podcast_feed(&self, dir: &str, base_url: &str) -> Result<String, io::Error> {
+        let dir = dir.trim_matches('/');
+        let base_url = base_url.trim_end_matches('/');
+        let mut episodes = Vec::new();
+        for node in self.list_dir(dir).await? {
+            let mime = match feed::mime_type(&node.name) {
+                Some(m) if node.children.is_none() && m.starts_with("audio/") => m,
+                _ => continue,
+            };
+            let rel_path = if dir.is_empty() {
+                node.name.clone()
+            } else {
+                format!("{}/{}", dir, node.name)
+            };
+            let disk_path = format!("{}/{}", self.FULL_ROOT_PATH, rel_path);
+            let file_tags = match tags::read_tags(disk_path.as_str()).await {
+                Ok(t) => t,
+                Err(e) => {
+                    log_err(
+                        format!("Error reading tags of {}, using file name: {}", rel_path, e)
+                            .as_str(),
+                        log::LogPriority::Low,
+                    );
+                    tags::Tags::default()
+                }
+            };
+            let stem = match node.name.rsplit_once('.') {
+                Some((stem, _)) => stem.to_string(),
+                None => node.name.clone(),
+            };
+            let title = file_tags.title.unwrap_or(stem);
+            episodes.push(feed::Episode {
+                title,
+                author: file_tags.artist,
+                episode: file_tags.track,
+                url: format!("{}/{}", base_url, feed::url_encode_path(&rel_path)),
+                length: node.size,
+                mime,
+                published: node.modified,
+            });
+        }
+        episodes.sort_by_key(|e| std::cmp::Reverse(e.published));
+
+        let title = match dir.rsplit('/').next() {
+            Some(name) if !name.is_empty() => name,
+            _ => self.head.name.as_str(),
+        };
+        Ok(feed::podcast_rss(title, base_url, &episodes))
+    }
+
+    /// Builds an OPDS catalog for the directory `dir`, so ebook readers can browse it
+    /// Subdirectories become subsections at `catalog_url/<path>`, and EPUB, PDF, and comic
+    /// archives become downloads at `files_url/<path>`. Everything else is left out
+    pub async fn opds_catalog(
+        &self,
+        dir: &str,
+        catalog_url: &str,
+        files_url: &str,
+    ) -> Result<String, io::Error> {
+        let dir = dir.trim_matches('/');
+        let catalog_url = catalog_url.trim_end_matches('/');
+        let files_url = files_url.trim_end_matches('/');
+        let mut entries = Vec::new();
+        for node in self.list_dir(dir).await? {
+            let rel_path = if dir.is_empty() {
+                node.name.clone()
+            } else {
+                format!("{}/{}", dir, node.name)
+            };
+            if node.children.is_some() {
+                entries.push(feed::OpdsEntry::Navigation {
+                    title: node.name.clone(),
+                    url: format!("{}/{}", catalog_url, feed::url_encode_path(&rel_path)),
+                    updated: node.modified,
+                });
+                continue;
+            }
+            let mime = match feed::mime_type(&node.name) {
+                Some(m) if feed::BOOK_MIME_TYPES.contains(&m) => m,
+                _ => continue,
+            };
+            let title = match node.name.rsplit_once('.') {
+                Some((stem, _)) => stem.to_string(),
+                None => node.name.clone(),
+            };
+            entries.push(feed::OpdsEntry::Acquisition {
+                title,
+                url: format!("{}/{}", files_url, feed::url_encode_path(&rel_path)),
+                mime,
+                length: node.size,
+                updated: node.modified,
+            });
+        }
+
+        let self_url = if dir.is_empty() {
+            catalog_url.to_string()
+        } else {
+            format!("{}/{}", catalog_url, feed::url_encode_path(dir))
+        };
+        let title = match dir.rsplit('/').next() {
+            Some(name) if !name.is_empty() => name,
+            _ => self.head.name.as_str(),
+        };
+        Ok(feed::opds_catalog(title, &self_url, &entries))
+    }
+
+    /// Returns the subtitle file at `path` as UTF-8 WebVTT, converting it from SRT if needed
+    /// The converted subtitle is cached by the source's content hash, so repeat requests
+    /// don't redo the charset detection and conversion, even after the source is renamed
+    pub async fn get_subtitle_vtt(&self, path: &str) -> Result<Arc<Vec<u8>>, io::Error> {
+        let lower = path.to_lowercase();
+        let is_srt = lower.ends_with(".srt");
+        if !is_srt && !lower.ends_with(".vtt") {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Error: {} is not an SRT or WebVTT subtitle file", path),
+            ));
+        }
+
+        let source_hash = self.file_hash(path, Algorithm::Sha256).await?;
+        let key = format!("vtt:{}", hash::to_hex(&source_hash));
+        if let Some(s) = self.derived.lock().unwrap().get(&key) {
+            return Ok(s.clone());
+        }
+        if let Some(ref store) = self.artifacts {
+            if let Some(stored) = store.get(&source_hash, "vtt").await? {
+                let stored = Arc::new(stored);
+                self.derived.lock().unwrap().put(key, stored.clone());
+                return Ok(stored);
+            }
+        }
+
+        let raw = self.get_file(path).await?;
+        let text = subtitle::decode_subtitle(&raw);
+        let vtt = if is_srt {
+            subtitle::srt_to_vtt(&text)
+        } else {
+            text
+        };
+
+        let vtt = Arc::new(vtt.into_bytes());
+        if let Some(ref store) = self.artifacts {
+            if let Err(e) = store.put(&source_hash, "vtt", &vtt).await {
+                log_err(
+                    format!("Error saving converted subtitle for {}: {}", path, e).as_str(),
+                    log::LogPriority::Low,
+                );
+            }
+        }
+        self.derived.lock().unwrap().put(key, vtt.clone());
+        Ok(vtt)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempDir;
 
     const TEST_DIR_PATH: &str = "../test_dir";
     #[tokio::test]
@@ -239,4 +794,247 @@ mod tests {
         let file = file_map.get_file("testfile1.txt").await.unwrap();
         assert_eq!(file.len(), 13); // test_file.txt has 13 bytes
     }
+
+    #[tokio::test]
+    async fn test_get_file_ref() {
+        let file_map = FileMap::from_root_dir(TEST_DIR_PATH).await.unwrap();
+        // A single component path used to return the root node
+        let file = file_map.get_file_ref("testfile1.txt").await.unwrap();
+        assert_eq!(file.name, "testfile1.txt");
+        assert_eq!(file_map.get_file_ref("test2/").await.unwrap().name, "test2");
+        assert!(file_map.get_file_ref("missing.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_chunk_size() {
+        let file_map = FileMap::from_root_dir(TEST_DIR_PATH)
+            .await
+            .unwrap()
+            .with_read_chunk_size(5000);
+        assert_eq!(file_map.read_chunk_size(), 8192);
+        let file = file_map.get_file("test2/episode.mp3").await.unwrap();
+        assert_eq!(file.len(), 167);
+        assert_eq!(&file[..3], b"ID3");
+    }
+
+    #[tokio::test]
+    async fn test_subtitle_conversion() {
+        let file_map = FileMap::from_root_dir(TEST_DIR_PATH).await.unwrap();
+        let vtt = file_map.get_subtitle_vtt("test2/subs.srt").await.unwrap();
+        let vtt = String::from_utf8(vtt.to_vec()).unwrap();
+        // subs.srt is saved as Windows-1252
+        assert_eq!(vtt, "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nCaf\u{e9}\n");
+        assert!(file_map.get_subtitle_vtt("testfile1.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_content_addressed_artifacts() {
+        let base = TempDir::new("derived");
+        let root = base.join("library");
+        let store = base.join("artifacts");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::copy("../test_dir/test2/subs.srt", root.join("a.srt")).unwrap();
+        std::fs::copy("../test_dir/test2/subs.srt", root.join("b.srt")).unwrap();
+
+        let file_map = FileMap::from_root_dir(root.to_str().unwrap())
+            .await
+            .unwrap()
+            .with_artifact_dir(store.to_str().unwrap());
+        let converted = file_map.get_subtitle_vtt("a.srt").await.unwrap();
+        // Identical files share the one artifact on disk
+        let stored: Vec<_> = std::fs::read_dir(&store).unwrap().collect();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(file_map.get_subtitle_vtt("b.srt").await.unwrap(), converted);
+
+        // A fresh map over the renamed file finds the stored artifact instead of converting
+        file_map.rename_path("a.srt", "moved/c.srt").await.unwrap();
+        let hash = file_map
+            .file_hash("moved/c.srt", Algorithm::Sha256)
+            .await
+            .unwrap();
+        let fresh = FileMap::from_root_dir(root.to_str().unwrap())
+            .await
+            .unwrap()
+            .with_artifact_dir(store.to_str().unwrap());
+        ArtifactStore::new(store.to_str().unwrap())
+            .put(&hash, "vtt", b"WEBVTT\n\nfrom the store\n")
+            .await
+            .unwrap();
+        let from_store = fresh.get_subtitle_vtt("moved/c.srt").await.unwrap();
+        assert_eq!(from_store.as_slice(), b"WEBVTT\n\nfrom the store\n");
+    }
+
+    #[tokio::test]
+    async fn test_podcast_feed() {
+        let file_map = FileMap::from_root_dir(TEST_DIR_PATH).await.unwrap();
+        let rss = file_map.podcast_feed("test2", "http://device:8080/files/").await.unwrap();
+        assert!(rss.contains("<title>test2</title>"));
+        assert!(rss.contains("<title>Pilot &amp; Friends</title>"));
+        assert!(rss.contains("<itunes:author>Host</itunes:author>"));
+        assert!(rss.contains("url=\"http://device:8080/files/test2/episode.mp3\""));
+        // Subtitles in the same directory aren't episodes
+        assert!(!rss.contains("subs.srt"));
+        assert!(file_map.podcast_feed("testfile1.txt", "http://device").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_grouping() {
+        let file_map = FileMap::from_root_dir(TEST_DIR_PATH).await.unwrap();
+        let rules = vec![GroupRule::new(&["mp3"], &["{stem}.nfo", "*.srt"])];
+        let groups = file_map.list_grouped("test2", &rules).await.unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].primary, "test2/book.epub");
+        assert_eq!(groups[1].primary, "test2/episode.mp3");
+        assert_eq!(groups[1].companions, vec!["test2/episode.nfo", "test2/subs.srt"]);
+
+        let group = file_map.get_group("test2/subs.srt", &rules).await.unwrap();
+        assert_eq!(group.primary, "test2/episode.mp3");
+        let companion = file_map.get_file(&group.companions[0]).await.unwrap();
+        assert_eq!(companion.as_slice(), b"<episodedetails/>\n");
+        assert!(file_map.get_group("test2/missing.srt", &rules).await.is_err());
+
+        // Directories are listed alongside the groups rather than dropped
+        let root = file_map.list_grouped("", &rules).await.unwrap();
+        let dirs: Vec<&MediaGroup> = root.iter().filter(|g| g.is_dir).collect();
+        assert_eq!(dirs.len(), 1);
+        assert_eq!(dirs[0].primary, "test2");
+        assert_eq!(root.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_zip_dir() {
+        let file_map = FileMap::from_root_dir(TEST_DIR_PATH).await.unwrap();
+        let mut stream = file_map.zip_dir("").await.unwrap();
+        let mut archive = Vec::new();
+        while let Some(chunk) = stream.next_chunk().await {
+            archive.extend_from_slice(&chunk.unwrap());
+        }
+        // test_dir/, test_dir/test2/, and the 6 files
+        let eocd = &archive[archive.len() - 22..];
+        assert_eq!(u16::from_le_bytes([eocd[10], eocd[11]]), 8);
+        assert!(archive.windows(23).any(|w| w == b"test_dir/test2/subs.srt"));
+        assert!(file_map.zip_dir("testfile1.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_checksum_manifests() {
+        let file_map = FileMap::from_root_dir(TEST_DIR_PATH).await.unwrap();
+        let txt_hash = "c77b5f295e6737572d4b5124b8fe276db6adaa0e2f044a2df2ff94be7040e265";
+        let empty_hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        let single = file_map
+            .checksum_manifests("", Algorithm::Sha256, false)
+            .await
+            .unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].0, "SHA256SUMS");
+        let lines: Vec<&str> = single[0].1.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], format!("{}  testfile1.txt", txt_hash));
+        assert_eq!(lines[1], format!("{}  testfile2.mp4", empty_hash));
+        assert!(lines[2].ends_with("  test2/book.epub"));
+
+        let per_dir = file_map
+            .checksum_manifests("", Algorithm::Sha256, true)
+            .await
+            .unwrap();
+        assert_eq!(per_dir.len(), 2);
+        assert_eq!(per_dir[0].1.lines().count(), 2);
+        assert_eq!(per_dir[1].0, "test2/SHA256SUMS");
+        assert!(per_dir[1].1.lines().all(|l| !l.contains('/')));
+
+        let sub = file_map
+            .checksum_manifests("test2", Algorithm::Sha256, false)
+            .await
+            .unwrap();
+        assert_eq!(sub[0].0, "test2/SHA256SUMS");
+        assert_eq!(sub[0].1, per_dir[1].1);
+
+        let b3 = file_map
+            .checksum_manifests("", Algorithm::Blake3, false)
+            .await
+            .unwrap();
+        assert_eq!(b3[0].0, "B3SUMS");
+        let empty_b3 = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+        assert_eq!(
+            b3[0].1.lines().nth(1).unwrap(),
+            format!("{}  testfile2.mp4", empty_b3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manifest_escaping() {
+        let root = TempDir::new("sums");
+        std::fs::write(root.join("line\nbreak.txt"), "").unwrap();
+        std::fs::write(root.join("back\\slash.txt"), "").unwrap();
+        let file_map = FileMap::from_root_dir(root.as_str()).await.unwrap();
+        let manifest = &file_map
+            .checksum_manifests("", Algorithm::Sha256, false)
+            .await
+            .unwrap()[0]
+            .1;
+        let empty_hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            manifest,
+            &format!(
+                "\\{0}  back\\\\slash.txt\n\\{0}  line\\nbreak.txt\n",
+                empty_hash
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_remove_rename() {
+        let root = TempDir::new("map");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let file_map = FileMap::from_root_dir(root.as_str()).await.unwrap();
+        let hash = file_map.file_hash("a.txt", Algorithm::Sha256).await.unwrap();
+        assert_eq!(file_map.get_file("a.txt").await.unwrap().as_slice(), b"hello");
+
+        file_map.rename_path("a.txt", "sub/dir/b.txt").await.unwrap();
+        assert!(!file_map.contains("a.txt").await);
+        assert!(file_map.contains("sub/dir/b.txt").await);
+        assert!(root.join("sub/dir/b.txt").exists());
+        // Cached contents and hash follow the file
+        assert!(file_map
+            .hashes
+            .lock()
+            .unwrap()
+            .contains_key(&("sub/dir/b.txt".to_string(), Algorithm::Sha256)));
+        assert_eq!(
+            file_map
+                .file_hash("sub/dir/b.txt", Algorithm::Sha256)
+                .await
+                .unwrap(),
+            hash
+        );
+        assert!(file_map.get_file("a.txt").await.is_err());
+
+        std::fs::write(root.join("c.txt"), "other").unwrap();
+        file_map.add_path("c.txt").await.unwrap();
+        assert!(file_map.rename_path("c.txt", "sub/dir/b.txt").await.is_err());
+        file_map.remove_path("c.txt").await.unwrap();
+        assert!(!file_map.contains("c.txt").await);
+        assert!(file_map.remove_path("c.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_opds_catalog() {
+        let file_map = FileMap::from_root_dir(TEST_DIR_PATH).await.unwrap();
+        let root = file_map
+            .opds_catalog("", "http://device/opds", "http://device/files")
+            .await
+            .unwrap();
+        assert!(root.contains("<link rel=\"subsection\" href=\"http://device/opds/test2\""));
+        // Non-book files aren't listed
+        assert!(!root.contains("testfile1.txt"));
+        let books = file_map
+            .opds_catalog("test2", "http://device/opds", "http://device/files")
+            .await
+            .unwrap();
+        assert!(books.contains("<id>http://device/opds/test2</id>"));
+        assert!(books.contains("href=\"http://device/files/test2/book.epub\""));
+        assert!(!books.contains("episode.mp3"));
+        assert!(!books.contains("subs.srt"));
+    }
 }
@@ -0,0 +1,219 @@
+/// Describes which files get grouped together into one logical item
+/// `primary` is a list of extensions (without the dot) for the main file of the item,
+/// `companions` is a list of file name patterns where `{stem}` matches the primary file's
+/// name minus its extension (literally, even if it contains `*`) and `*` matches anything
+/// Matching ignores case
+pub struct GroupRule {
+    pub primary: Vec<String>,
+    pub companions: Vec<String>,
+}
+
+impl GroupRule {
+    pub fn new(primary: &[&str], companions: &[&str]) -> GroupRule {
+        GroupRule {
+            primary: primary.iter().map(|s| s.to_lowercase()).collect(),
+            companions: companions.iter().map(|s| s.to_lowercase()).collect(),
+        }
+    }
+
+    /// RAW photos with their JPEG previews, and videos with subtitles, posters and NFOs
+    pub fn defaults() -> Vec<GroupRule> {
+        vec![
+            GroupRule::new(
+                &["cr2", "cr3", "nef", "arw", "dng", "raf", "orf", "rw2"],
+                &["{stem}.jpg", "{stem}.jpeg", "{stem}.xmp"],
+            ),
+            GroupRule::new(
+                &["mp4", "mkv", "m4v", "avi", "webm", "mov"],
+                &[
+                    "{stem}.srt",
+                    "{stem}.*.srt",
+                    "{stem}.vtt",
+                    "{stem}.*.vtt",
+                    "{stem}.nfo",
+                    "{stem}.jpg",
+                    "{stem}-poster.jpg",
+                    "{stem}-fanart.jpg",
+                ],
+            ),
+        ]
+    }
+}
+
+/// A primary file and the companion files that belong with it
+/// Files that don't match any rule end up in a group of their own with no companions,
+/// and so do directories, with `is_dir` set
+#[derive(Debug, PartialEq)]
+pub struct MediaGroup {
+    pub primary: String,
+    pub companions: Vec<String>,
+    pub is_dir: bool,
+}
+
+/// Turns a companion pattern into bytes to match, with `None` standing for `*`
+/// `{stem}` is filled in with `stem` as literal bytes, so a `*` in a file name stays a `*`
+fn compile_pattern(pattern: &str, stem: &str) -> Vec<Option<u8>> {
+    let mut compiled = Vec::new();
+    for (i, part) in pattern.split("{stem}").enumerate() {
+        if i > 0 {
+            compiled.extend(stem.bytes().map(Some));
+        }
+        compiled.extend(part.bytes().map(|b| if b == b'*' { None } else { Some(b) }));
+    }
+    compiled
+}
+
+/// Matches `name` against a compiled pattern where `None` matches any run of characters
+fn glob_match(pattern: &[Option<u8>], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((None, rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((Some(c), rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+fn split_ext(name: &str) -> (&str, &str) {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+        _ => (name, ""),
+    }
+}
+
+/// Groups the file names of a single directory according to `rules`
+/// Rules are tried in order and a file is only ever claimed by one group,
+/// so put the more specific rules first. Groups come back sorted by primary name
+pub fn group_files(names: &[String], rules: &[GroupRule]) -> Vec<MediaGroup> {
+    let mut sorted: Vec<&String> = names.iter().collect();
+    sorted.sort();
+    let lowered: Vec<String> = sorted.iter().map(|n| n.to_lowercase()).collect();
+
+    // Which rule (if any) each file is a primary for
+    let primary_rule: Vec<Option<&GroupRule>> = lowered
+        .iter()
+        .map(|name| {
+            let ext = split_ext(name).1;
+            rules.iter().find(|r| r.primary.iter().any(|p| p == ext))
+        })
+        .collect();
+
+    let mut claimed = vec![false; sorted.len()];
+    let mut groups = Vec::new();
+    for (i, rule) in primary_rule.iter().enumerate() {
+        let Some(rule) = rule else {
+            continue;
+        };
+        claimed[i] = true;
+        let stem = split_ext(&lowered[i]).0;
+        let patterns: Vec<Vec<Option<u8>>> = rule
+            .companions
+            .iter()
+            .map(|c| compile_pattern(c, stem))
+            .collect();
+        let mut companions = Vec::new();
+        for j in 0..sorted.len() {
+            if claimed[j] || primary_rule[j].is_some() {
+                continue;
+            }
+            if patterns
+                .iter()
+                .any(|p| glob_match(p, lowered[j].as_bytes()))
+            {
+                claimed[j] = true;
+                companions.push(sorted[j].clone());
+            }
+        }
+        groups.push(MediaGroup {
+            primary: sorted[i].clone(),
+            companions,
+            is_dir: false,
+        });
+    }
+    for (i, name) in sorted.iter().enumerate() {
+        if !claimed[i] {
+            groups.push(MediaGroup {
+                primary: (*name).clone(),
+                companions: Vec::new(),
+                is_dir: false,
+            });
+        }
+    }
+    groups.sort_by(|a, b| a.primary.cmp(&b.primary));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_default_grouping() {
+        let groups = group_files(
+            &names(&[
+                "IMG_001.CR2",
+                "IMG_001.JPG",
+                "IMG_002.jpg",
+                "Movie.mkv",
+                "Movie.en.srt",
+                "Movie-poster.jpg",
+                "Movie.nfo",
+                "notes.txt",
+            ]),
+            &GroupRule::defaults(),
+        );
+        assert_eq!(
+            groups,
+            vec![
+                MediaGroup {
+                    primary: "IMG_001.CR2".to_string(),
+                    companions: names(&["IMG_001.JPG"]),
+                    is_dir: false,
+                },
+                MediaGroup {
+                    primary: "IMG_002.jpg".to_string(),
+                    companions: Vec::new(),
+                    is_dir: false,
+                },
+                MediaGroup {
+                    primary: "Movie.mkv".to_string(),
+                    companions: names(&["Movie-poster.jpg", "Movie.en.srt", "Movie.nfo"]),
+                    is_dir: false,
+                },
+                MediaGroup {
+                    primary: "notes.txt".to_string(),
+                    companions: Vec::new(),
+                    is_dir: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_companion_claimed_once() {
+        // Both videos would match "*.srt", only the first one gets it
+        let rules = vec![GroupRule::new(&["mp4"], &["*.srt"])];
+        let groups = group_files(&names(&["a.mp4", "b.mp4", "subs.srt"]), &rules);
+        assert_eq!(groups[0].companions, names(&["subs.srt"]));
+        assert!(groups[1].companions.is_empty());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let pattern = compile_pattern("{stem}.*.srt", "movie");
+        assert!(glob_match(&pattern, b"movie.en.srt"));
+        assert!(!glob_match(&pattern, b"movie.srt"));
+        assert!(glob_match(&compile_pattern("*", "movie"), b""));
+    }
+
+    #[test]
+    fn test_stem_matched_literally() {
+        // A "*" in the video's name must not act as a wildcard
+        let rules = vec![GroupRule::new(&["mkv"], &["{stem}.srt"])];
+        let groups = group_files(&names(&["a*.mkv", "a*.srt", "ab.srt"]), &rules);
+        assert_eq!(groups[0].companions, names(&["a*.srt"]));
+        assert_eq!(groups[1].primary, "ab.srt");
+    }
+}
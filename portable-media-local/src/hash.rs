@@ -0,0 +1,132 @@
+use std::io::Error;
+
+use sha2::{Digest, Sha256};
+
+use crate::reader;
+
+/// The hashes files can be checked with. SHA-256 is what `sha256sum` and most other
+/// systems understand, BLAKE3 (as `b3sum` checks it) is a lot quicker on big media files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    Sha256,
+    Blake3,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 2] = [Algorithm::Sha256, Algorithm::Blake3];
+
+    /// The usual file name for a manifest of this kind of hash
+    pub fn manifest_name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "SHA256SUMS",
+            Algorithm::Blake3 => "B3SUMS",
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Hasher {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    fn finish(self) -> [u8; 32] {
+        match self {
+            Hasher::Sha256(h) => h.finalize().into(),
+            Hasher::Blake3(h) => *h.finalize().as_bytes(),
+        }
+    }
+}
+
+/// Hashes the file at `path` without reading all of it into memory at once,
+/// reading `chunk_size` bytes at a time (see `reader::read_chunks`)
+pub async fn hash_file(
+    path: &str,
+    algorithm: Algorithm,
+    chunk_size: usize,
+) -> Result<[u8; 32], Error> {
+    let hasher = reader::read_chunks(
+        path.to_string(),
+        chunk_size,
+        Hasher::new(algorithm),
+        |hasher, chunk| hasher.update(chunk),
+    )
+    .await?;
+    Ok(hasher.finish())
+}
+
+pub fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Formats one line of a `sha256sum`/`b3sum` style manifest
+/// Names with a backslash or line break in them are escaped, with a backslash
+/// in front of the line to say so, the same way `sha256sum` writes them
+pub fn manifest_line(hash: &[u8], name: &str) -> String {
+    if !name.contains(['\\', '\n', '\r']) {
+        return format!("{}  {}\n", to_hex(hash), name);
+    }
+    let escaped = name
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\\{}  {}\n", to_hex(hash), escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hash_file() {
+        let hash = hash_file("../test_dir/testfile1.txt", Algorithm::Sha256, 4)
+            .await
+            .unwrap();
+        assert_eq!(
+            to_hex(&hash),
+            "c77b5f295e6737572d4b5124b8fe276db6adaa0e2f044a2df2ff94be7040e265"
+        );
+        let hash = hash_file("../test_dir/testfile2.mp4", Algorithm::Blake3, 4096)
+            .await
+            .unwrap();
+        assert_eq!(
+            to_hex(&hash),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        // A chunk size of 0 doesn't divide by zero
+        let hash = hash_file("../test_dir/testfile1.txt", Algorithm::Sha256, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            to_hex(&hash),
+            "c77b5f295e6737572d4b5124b8fe276db6adaa0e2f044a2df2ff94be7040e265"
+        );
+    }
+
+    #[test]
+    fn test_manifest_line() {
+        let hash = [0xab; 32];
+        let hex = "ab".repeat(32);
+        assert_eq!(manifest_line(&hash, "a b.mp3"), format!("{}  a b.mp3\n", hex));
+        assert_eq!(
+            manifest_line(&hash, "a\\b\nc.mp3"),
+            format!("\\{}  a\\\\b\\nc.mp3\n", hex)
+        );
+    }
+}
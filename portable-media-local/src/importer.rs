@@ -0,0 +1,624 @@
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::fs;
+use tokio::task::JoinHandle;
+
+use crate::file_map::FileMap;
+use crate::log::{self, log, log_err};
+use crate::organize;
+use crate::tags;
+
+/// Name of the file in the inbox that records what was imported, so it can be undone
+pub const JOURNAL_NAME: &str = ".import-journal";
+
+/// Where new files get dropped and how they get named once they're in the library
+pub struct ImportConfig {
+    pub inbox: String,
+    pub template: String,
+    /// Files modified more recently than this are assumed to still be copying in
+    pub settle: Duration,
+}
+
+impl ImportConfig {
+    pub fn new(inbox: &str) -> ImportConfig {
+        ImportConfig {
+            inbox: inbox.trim_end_matches('/').to_string(),
+            template: organize::DEFAULT_TEMPLATE.to_string(),
+            settle: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A file leaving the inbox: `from` is its path on disk, `to` its path in the library
+#[derive(Debug, Clone, PartialEq)]
+pub struct Move {
+    pub from: String,
+    pub to: String,
+}
+
+/// What an import would do, without having touched anything yet
+/// `skipped` holds inbox files that stay where they are, and why
+#[derive(Debug, Default)]
+pub struct ImportPlan {
+    pub moves: Vec<Move>,
+    pub skipped: Vec<(String, String)>,
+}
+
+/// The moves an import actually made, in order, and the library directories it had
+/// to create for them (parents before children)
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportJournal {
+    pub moves: Vec<Move>,
+    pub created_dirs: Vec<String>,
+}
+
+impl ImportJournal {
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty() && self.created_dirs.is_empty()
+    }
+
+    /// One tab separated record per line, with tabs, line breaks, and backslashes
+    /// in paths escaped so an odd file name can't break the record it's in
+    fn to_lines(&self) -> String {
+        let mut contents = String::new();
+        for dir in &self.created_dirs {
+            contents.push_str(&format!("mkdir\t{}\n", escape(dir)));
+        }
+        for m in &self.moves {
+            contents.push_str(&format!("move\t{}\t{}\n", escape(&m.from), escape(&m.to)));
+        }
+        contents
+    }
+
+    /// Appends the journal to the file at `path`
+    pub async fn append_to(&self, path: &str) -> Result<(), Error> {
+        let mut contents = match fs::read_to_string(path).await {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        contents.push_str(&self.to_lines());
+        write_replacing(path, &contents).await
+    }
+
+    /// Replaces the file at `path` with the journal
+    pub async fn save_to(&self, path: &str) -> Result<(), Error> {
+        write_replacing(path, &self.to_lines()).await
+    }
+
+    pub async fn load(path: &str) -> Result<ImportJournal, Error> {
+        let contents = fs::read_to_string(path).await?;
+        let mut journal = ImportJournal::default();
+        for line in contents.lines().filter(|l| !l.is_empty()) {
+            let fields: Vec<String> = line.split('\t').map(unescape).collect();
+            match fields.as_slice() {
+                [kind, dir] if kind == "mkdir" => journal.created_dirs.push(dir.clone()),
+                [kind, from, to] if kind == "move" => journal.moves.push(Move {
+                    from: from.clone(),
+                    to: to.clone(),
+                }),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Error: bad line in import journal {}: {}", path, line),
+                    ))
+                }
+            }
+        }
+        Ok(journal)
+    }
+}
+
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('\\') => out.push('\\'),
+            // Anything else was written before escaping existed, so it's kept as is
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`,
+/// so a crash partway through leaves the old file rather than half of the new one
+async fn write_replacing(path: &str, contents: &str) -> Result<(), Error> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await
+}
+
+/// Lists every file under `dir` on disk, skipping dotfiles (like the journal)
+async fn scan(dir: &str) -> Result<Vec<String>, Error> {
+    let mut files = Vec::new();
+    let mut stack = vec![dir.to_string()];
+    while let Some(dir) = stack.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                log_err(
+                    format!("Error: file in {} not valid unicode, skipping file", dir).as_str(),
+                    log::LogPriority::Middle,
+                );
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = format!("{}/{}", dir, name);
+            if entry.file_type().await?.is_dir() {
+                stack.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Works out where every file in the inbox would go, without moving anything
+/// This is the dry run, `apply_import` carries a plan out
+pub async fn plan_import(map: &FileMap, config: &ImportConfig) -> Result<ImportPlan, Error> {
+    let mut plan = ImportPlan::default();
+    let now = SystemTime::now();
+    for from in scan(&config.inbox).await? {
+        let modified = fs::metadata(&from).await?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() < config.settle {
+            plan.skipped.push((from, "still being written".to_string()));
+            continue;
+        }
+        let file_tags = match tags::read_tags(&from).await {
+            Ok(t) => t,
+            Err(e) => {
+                plan.skipped.push((from, e.to_string()));
+                continue;
+            }
+        };
+        let file_name = from.rsplit('/').next().unwrap_or(&from);
+        let to = match organize::render_template(&config.template, &file_tags, file_name) {
+            Ok(to) => to,
+            Err(e) => {
+                plan.skipped.push((from, e.to_string()));
+                continue;
+            }
+        };
+        if map.contains(&to).await
+            || fs::try_exists(map.disk_path(&to)).await?
+            || plan.moves.iter().any(|m| m.to == to)
+        {
+            plan.skipped.push((from, format!("{} already exists", to)));
+            continue;
+        }
+        plan.moves.push(Move { from, to });
+    }
+    Ok(plan)
+}
+
+/// Moves a file, falling back to copy and delete when it's crossing filesystems
+/// (the inbox is often on a different card than the library)
+async fn move_file(from: &str, to: &str) -> Result<(), Error> {
+    if let Some(parent) = Path::new(to).parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    match fs::rename(from, to).await {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            fs::copy(from, to).await?;
+            fs::remove_file(from).await
+        }
+        result => result,
+    }
+}
+
+/// Carries out an import plan, moving files into the library and adding them to the map
+/// A file that fails to move is logged and left in the inbox, the rest carry on
+pub async fn apply_import(map: &FileMap, plan: &ImportPlan) -> ImportJournal {
+    let mut journal = ImportJournal::default();
+    for m in &plan.moves {
+        // Parent directories that don't exist yet, so undo knows to take them away again
+        let mut missing_dirs = Vec::new();
+        if let Some((parent, _)) = m.to.rsplit_once('/') {
+            let mut dir = String::new();
+            for part in parent.split('/') {
+                if !dir.is_empty() {
+                    dir.push('/');
+                }
+                dir.push_str(part);
+                if !map.contains(&dir).await {
+                    missing_dirs.push(dir.clone());
+                }
+            }
+        }
+        if let Err(e) = move_file(&m.from, &map.disk_path(&m.to)).await {
+            log_err(
+                format!("Error importing {} to {}: {}", m.from, m.to, e).as_str(),
+                log::LogPriority::Middle,
+            );
+            continue;
+        }
+        // The file has left the inbox either way, so it's journaled even if the map
+        // can't take it, otherwise it could never be undone
+        journal.created_dirs.append(&mut missing_dirs);
+        journal.moves.push(m.clone());
+        if let Err(e) = map.add_path(&m.to).await {
+            log_err(
+                format!("Error adding imported {} to the map: {}", m.to, e).as_str(),
+                log::LogPriority::Middle,
+            );
+        }
+    }
+    journal
+}
+
+/// What undoing an import did
+/// `undone` are the files put back, with `from` being where each one actually went
+/// `remaining` is what couldn't be undone this time and is worth trying again later
+#[derive(Debug, Default, PartialEq)]
+pub struct UndoOutcome {
+    pub undone: Vec<Move>,
+    pub remaining: ImportJournal,
+}
+
+/// Puts one imported file back in the inbox, returning where it went
+/// If something new has shown up at its old path since, it goes back under
+/// a " (2)" style name rather than overwriting that
+async fn undo_move(map: &FileMap, m: &Move) -> Result<String, Error> {
+    // A path that can't even be checked can't be moved to either, so the move reports it
+    let back_to = organize::free_name(&m.from, |candidate| async move {
+        fs::try_exists(&candidate).await.unwrap_or(false)
+    })
+    .await;
+    move_file(&map.disk_path(&m.to), &back_to).await?;
+    if map.contains(&m.to).await {
+        map.remove_path(&m.to).await?;
+    }
+    Ok(back_to)
+}
+
+/// Puts every file in `journal` back where it came from, newest first, then removes
+/// the directories the import created if nothing else has been put in them since
+/// Files that have since been moved or deleted from the library are dropped from the
+/// journal, since there's nothing left to undo. Files that fail to go back are logged
+/// and stay in the returned `remaining` journal, along with the directories they're in
+pub async fn undo_import(map: &FileMap, journal: &ImportJournal) -> UndoOutcome {
+    let mut outcome = UndoOutcome::default();
+    for m in journal.moves.iter().rev() {
+        if !fs::try_exists(map.disk_path(&m.to)).await.unwrap_or(true) {
+            log_err(
+                format!(
+                    "Error undoing import of {}: {} is gone from the library",
+                    m.from, m.to
+                )
+                .as_str(),
+                log::LogPriority::Middle,
+            );
+            continue;
+        }
+        match undo_move(map, m).await {
+            Ok(back_to) => outcome.undone.push(Move {
+                from: back_to,
+                to: m.to.clone(),
+            }),
+            Err(e) => {
+                log_err(
+                    format!("Error undoing import of {} to {}: {}", m.from, m.to, e).as_str(),
+                    log::LogPriority::Middle,
+                );
+                outcome.remaining.moves.push(m.clone());
+            }
+        }
+    }
+    outcome.remaining.moves.reverse();
+
+    for dir in journal.created_dirs.iter().rev() {
+        // Fails when the directory isn't empty, in which case it's staying anyway
+        if fs::remove_dir(map.disk_path(dir)).await.is_ok() {
+            if let Err(e) = map.remove_path(dir).await {
+                log_err(
+                    format!("Error removing {} from the map: {}", dir, e).as_str(),
+                    log::LogPriority::Low,
+                );
+            }
+            continue;
+        }
+        let under = format!("{}/", dir);
+        if outcome
+            .remaining
+            .moves
+            .iter()
+            .any(|m| m.to.starts_with(&under))
+        {
+            outcome.remaining.created_dirs.push(dir.clone());
+        }
+    }
+    outcome.remaining.created_dirs.reverse();
+    outcome
+}
+
+/// Plans and applies an import, appending what moved to the inbox's journal
+pub async fn import_inbox(map: &FileMap, config: &ImportConfig) -> Result<ImportJournal, Error> {
+    let plan = plan_import(map, config).await?;
+    let journal = apply_import(map, &plan).await;
+    // Nothing to record, and watch mode would otherwise rewrite the journal every tick
+    if journal.is_empty() {
+        return Ok(journal);
+    }
+    journal
+        .append_to(&format!("{}/{}", config.inbox, JOURNAL_NAME))
+        .await?;
+    Ok(journal)
+}
+
+/// Undoes everything recorded in the inbox's journal, leaving only what couldn't be
+/// undone in it (or removing it once everything has been)
+pub async fn undo_inbox(map: &FileMap, config: &ImportConfig) -> Result<UndoOutcome, Error> {
+    let journal_path = format!("{}/{}", config.inbox, JOURNAL_NAME);
+    let journal = ImportJournal::load(&journal_path).await?;
+    let outcome = undo_import(map, &journal).await;
+    if outcome.remaining.is_empty() {
+        fs::remove_file(&journal_path).await?;
+    } else {
+        outcome.remaining.save_to(&journal_path).await?;
+    }
+    Ok(outcome)
+}
+
+/// Checks the inbox every `every` and imports whatever has settled there
+pub fn watch_inbox(map: Arc<FileMap>, config: ImportConfig, every: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match import_inbox(&map, &config).await {
+                Ok(journal) => {
+                    for m in &journal.moves {
+                        log(
+                            format!("Imported {} to {}", m.from, m.to).as_str(),
+                            log::LogPriority::Low,
+                        );
+                    }
+                }
+                Err(e) => log_err(
+                    format!("Error importing from inbox {}: {}", config.inbox, e).as_str(),
+                    log::LogPriority::High,
+                ),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// Sets up a throwaway library and inbox, since these tests move files around
+    async fn setup(name: &str) -> (TempDir, String, String) {
+        let base = TempDir::new(&format!("import-{}", name));
+        let library = base.join("library");
+        let inbox = base.join("inbox");
+        fs::create_dir_all(library.join("Host")).await.unwrap();
+        fs::create_dir_all(inbox.join("nested")).await.unwrap();
+        fs::copy("../test_dir/test2/episode.mp3", inbox.join("nested/dl.mp3"))
+            .await
+            .unwrap();
+        fs::copy("../test_dir/testfile1.txt", inbox.join("notes.txt"))
+            .await
+            .unwrap();
+        (
+            base,
+            library.to_string_lossy().into_owned(),
+            inbox.to_string_lossy().into_owned(),
+        )
+    }
+
+    fn config(inbox: &str) -> ImportConfig {
+        ImportConfig {
+            template: "{artist}/{track:02} - {title}.{ext}".to_string(),
+            settle: Duration::ZERO,
+            ..ImportConfig::new(inbox)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let (_dir, library, inbox) = setup("dry").await;
+        let map = FileMap::from_root_dir(&library).await.unwrap();
+        let plan = plan_import(&map, &config(&inbox)).await.unwrap();
+        assert_eq!(
+            plan.moves,
+            vec![Move {
+                from: format!("{}/nested/dl.mp3", inbox),
+                to: "Host/01 - Pilot & Friends.mp3".to_string(),
+            }]
+        );
+        // Untagged text file stays behind
+        assert_eq!(plan.skipped.len(), 1);
+        assert!(plan.skipped[0].0.ends_with("notes.txt"));
+        assert!(fs::try_exists(&plan.moves[0].from).await.unwrap());
+
+        let settling = ImportConfig {
+            settle: Duration::from_secs(3600),
+            ..config(&inbox)
+        };
+        assert!(plan_import(&map, &settling).await.unwrap().moves.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import_and_undo() {
+        let (_dir, library, inbox) = setup("undo").await;
+        let map = FileMap::from_root_dir(&library).await.unwrap();
+        let journal = import_inbox(&map, &config(&inbox)).await.unwrap();
+        assert_eq!(journal.moves.len(), 1);
+        let imported = map.get_file("Host/01 - Pilot & Friends.mp3").await.unwrap();
+        assert_eq!(imported.len(), 167);
+        assert!(!fs::try_exists(format!("{}/nested/dl.mp3", inbox))
+            .await
+            .unwrap());
+
+        // Importing again has nothing left to do
+        assert!(plan_import(&map, &config(&inbox))
+            .await
+            .unwrap()
+            .moves
+            .is_empty());
+
+        let outcome = undo_inbox(&map, &config(&inbox)).await.unwrap();
+        assert_eq!(outcome.undone, journal.moves);
+        assert!(outcome.remaining.is_empty());
+        assert!(!fs::try_exists(format!("{}/{}", inbox, JOURNAL_NAME))
+            .await
+            .unwrap());
+        assert!(fs::try_exists(format!("{}/nested/dl.mp3", inbox))
+            .await
+            .unwrap());
+        assert!(!map.contains("Host/01 - Pilot & Friends.mp3").await);
+        // Host already existed before the import so it stays
+        assert!(map.contains("Host").await);
+        assert!(fs::try_exists(map.disk_path("Host")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_undo_removes_created_dirs() {
+        let (_dir, library, inbox) = setup("dirs").await;
+        let map = FileMap::from_root_dir(&library).await.unwrap();
+        let config = ImportConfig {
+            template: "{artist}/New/{title}.{ext}".to_string(),
+            ..config(&inbox)
+        };
+        let journal = import_inbox(&map, &config).await.unwrap();
+        assert_eq!(journal.created_dirs, vec!["Host/New"]);
+        assert!(map.contains("Host/New/Pilot & Friends.mp3").await);
+
+        undo_inbox(&map, &config).await.unwrap();
+        assert!(!map.contains("Host/New").await);
+        assert!(!fs::try_exists(map.disk_path("Host/New")).await.unwrap());
+        assert!(map.contains("Host").await);
+    }
+
+    #[tokio::test]
+    async fn test_undo_keeps_new_inbox_file() {
+        let (_dir, library, inbox) = setup("clash").await;
+        let map = FileMap::from_root_dir(&library).await.unwrap();
+        import_inbox(&map, &config(&inbox)).await.unwrap();
+        // Something else with the same name gets dropped in after the import
+        let dropped = format!("{}/nested/dl.mp3", inbox);
+        fs::write(&dropped, "new download").await.unwrap();
+
+        let outcome = undo_inbox(&map, &config(&inbox)).await.unwrap();
+        assert_eq!(
+            outcome.undone[0].from,
+            format!("{}/nested/dl (2).mp3", inbox)
+        );
+        assert_eq!(fs::read(&dropped).await.unwrap(), b"new download");
+        assert_eq!(
+            fs::metadata(&outcome.undone[0].from).await.unwrap().len(),
+            167
+        );
+    }
+
+    #[tokio::test]
+    async fn test_undo_is_resumable() {
+        let (_dir, library, inbox) = setup("resume").await;
+        fs::create_dir_all(format!("{}/other", inbox))
+            .await
+            .unwrap();
+        fs::copy(
+            "../test_dir/test2/episode.mp3",
+            format!("{}/other/dl2.mp3", inbox),
+        )
+        .await
+        .unwrap();
+        let map = FileMap::from_root_dir(&library).await.unwrap();
+        let config = ImportConfig {
+            template: "{artist}/{stem}.{ext}".to_string(),
+            ..config(&inbox)
+        };
+        let journal = import_inbox(&map, &config).await.unwrap();
+        assert_eq!(journal.moves.len(), 2);
+
+        // One file gets renamed in the library, and the other can't go back for now
+        map.rename_path("Host/dl.mp3", "Host/renamed.mp3")
+            .await
+            .unwrap();
+        fs::remove_dir(format!("{}/other", inbox)).await.unwrap();
+        fs::write(format!("{}/other", inbox), "in the way")
+            .await
+            .unwrap();
+
+        let outcome = undo_inbox(&map, &config).await.unwrap();
+        assert!(outcome.undone.is_empty());
+        assert_eq!(outcome.remaining.moves, vec![journal.moves[1].clone()]);
+        let journal_path = format!("{}/{}", inbox, JOURNAL_NAME);
+        assert_eq!(
+            ImportJournal::load(&journal_path).await.unwrap(),
+            outcome.remaining
+        );
+        assert!(map.contains("Host/renamed.mp3").await);
+
+        // Once the way is clear the rest of the undo goes through
+        fs::remove_file(format!("{}/other", inbox)).await.unwrap();
+        let outcome = undo_inbox(&map, &config).await.unwrap();
+        assert_eq!(outcome.undone, vec![journal.moves[1].clone()]);
+        assert!(!fs::try_exists(&journal_path).await.unwrap());
+        assert!(!map.contains("Host/dl2.mp3").await);
+    }
+
+    #[tokio::test]
+    async fn test_journal_escaping() {
+        let (_dir, library, inbox) = setup("escape").await;
+        let map = FileMap::from_root_dir(&library).await.unwrap();
+        let journal_path = format!("{}/{}", inbox, JOURNAL_NAME);
+        // An import with nothing to do doesn't leave a journal behind
+        let settling = ImportConfig {
+            settle: Duration::from_secs(3600),
+            ..config(&inbox)
+        };
+        assert!(import_inbox(&map, &settling).await.unwrap().is_empty());
+        assert!(!fs::try_exists(&journal_path).await.unwrap());
+
+        fs::rename(
+            format!("{}/nested/dl.mp3", inbox),
+            format!("{}/nested/tab\there\nback\\slash.mp3", inbox),
+        )
+        .await
+        .unwrap();
+        let config = ImportConfig {
+            template: "{artist}/{stem}.{ext}".to_string(),
+            ..config(&inbox)
+        };
+        let journal = import_inbox(&map, &config).await.unwrap();
+        assert!(journal.moves[0]
+            .from
+            .ends_with("/tab\there\nback\\slash.mp3"));
+        assert_eq!(ImportJournal::load(&journal_path).await.unwrap(), journal);
+
+        let outcome = undo_inbox(&map, &config).await.unwrap();
+        assert_eq!(outcome.undone, journal.moves);
+        assert!(fs::try_exists(&journal.moves[0].from).await.unwrap());
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{Error, ErrorKind};
+
+/// H.264 High profile level 4.0 video with AAC-LC audio, which covers every
+/// rendition up to 1080p
+pub const DEFAULT_CODECS: &str = "avc1.640028,mp4a.40.2";
+
+/// A single quality level in a bitrate ladder
+/// `bandwidth` is the peak bitrate in bits per second, as HLS expects it
+/// `codecs` is the RFC 6381 codec list advertised in the master playlist
+pub struct Rendition {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub bandwidth: u64,
+    pub codecs: String,
+}
+
+impl Rendition {
+    pub fn new(name: &str, width: u32, height: u32, bandwidth: u64) -> Rendition {
+        Rendition {
+            name: name.to_string(),
+            width,
+            height,
+            bandwidth,
+            codecs: DEFAULT_CODECS.to_string(),
+        }
+    }
+
+    pub fn with_codecs(mut self, codecs: &str) -> Rendition {
+        self.codecs = codecs.to_string();
+        self
+    }
+}
+
+/// The set of renditions generated for a stream, ordered from highest to lowest quality
+pub struct BitrateLadder {
+    renditions: Vec<Rendition>,
+}
+
+impl BitrateLadder {
+    /// Builds a ladder from a list of renditions
+    /// Returns an `io::Error` if the list is empty or two renditions share a name,
+    /// since the name is used as the directory of the rendition's media playlist
+    /// For the same reason names can't be empty or contain `"`, `,`, `/` or `..`
+    pub fn new(mut renditions: Vec<Rendition>) -> Result<BitrateLadder, Error> {
+        if renditions.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Error: bitrate ladder needs at least one rendition",
+            ));
+        }
+        for (i, r) in renditions.iter().enumerate() {
+            if r.name.is_empty() || r.name.contains(['"', ',', '/']) || r.name.contains("..") {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Error: invalid rendition name {:?} in bitrate ladder",
+                        r.name
+                    ),
+                ));
+            }
+            if r.codecs.contains('"') {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Error: invalid codecs {:?} for rendition {} in bitrate ladder",
+                        r.codecs, r.name
+                    ),
+                ));
+            }
+            if renditions[..i].iter().any(|other| other.name == r.name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Error: duplicate rendition name {} in bitrate ladder",
+                        r.name
+                    ),
+                ));
+            }
+        }
+        renditions.sort_by_key(|r| std::cmp::Reverse(r.bandwidth));
+        Ok(BitrateLadder { renditions })
+    }
+
+    pub fn renditions(&self) -> &[Rendition] {
+        &self.renditions
+    }
+
+    /// Drops every rendition taller than the source, since upscaling only wastes space
+    /// The lowest rendition is always kept so there is something to play
+    pub fn for_source_height(&self, source_height: u32) -> Vec<&Rendition> {
+        let mut kept: Vec<&Rendition> = self
+            .renditions
+            .iter()
+            .filter(|r| r.height <= source_height)
+            .collect();
+        if kept.is_empty() {
+            if let Some(lowest) = self.renditions.last() {
+                kept.push(lowest);
+            }
+        }
+        kept
+    }
+
+    /// Renders an HLS master playlist for a source `source_height` pixels tall,
+    /// referencing `<name>/index.m3u8` for every rendition `for_source_height` keeps
+    pub fn master_playlist(&self, source_height: u32) -> String {
+        let mut out = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+        for r in self.for_source_height(source_height) {
+            out.push_str(&format!(
+                "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{},CODECS=\"{}\"\n{}/index.m3u8\n",
+                r.bandwidth, r.width, r.height, r.codecs, r.name
+            ));
+        }
+        out
+    }
+}
+
+impl Default for BitrateLadder {
+    /// A conservative ladder that stays watchable over a phone hotspot at the bottom end
+    fn default() -> BitrateLadder {
+        BitrateLadder {
+            renditions: vec![
+                Rendition::new("1080p", 1920, 1080, 5_000_000),
+                Rendition::new("720p", 1280, 720, 2_800_000),
+                Rendition::new("480p", 854, 480, 1_400_000),
+                Rendition::new("360p", 640, 360, 800_000),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_master_playlist() {
+        let ladder = BitrateLadder::new(vec![
+            Rendition::new("low", 640, 360, 800_000),
+            Rendition::new("high", 1280, 720, 2_800_000),
+        ])
+        .unwrap();
+        let playlist = ladder.master_playlist(1080);
+        assert!(playlist.starts_with("#EXTM3U\n"));
+        // Highest quality comes first so players start there
+        let high = playlist.find("high/index.m3u8").unwrap();
+        let low = playlist.find("low/index.m3u8").unwrap();
+        assert!(high < low);
+        assert!(playlist
+            .contains("BANDWIDTH=800000,RESOLUTION=640x360,CODECS=\"avc1.640028,mp4a.40.2\"\n"));
+        assert!(!playlist.contains("NAME="));
+
+        // A 720p source isn't offered anything it would have to be upscaled for
+        let playlist = BitrateLadder::default().master_playlist(720);
+        assert!(!playlist.contains("1080p/index.m3u8"));
+        assert!(playlist.contains("720p/index.m3u8"));
+    }
+
+    #[test]
+    fn test_ladder_validation() {
+        assert!(BitrateLadder::new(Vec::new()).is_err());
+        assert!(BitrateLadder::new(vec![
+            Rendition::new("a", 640, 360, 800_000),
+            Rendition::new("a", 1280, 720, 2_800_000),
+        ])
+        .is_err());
+        for name in ["", "a\"b", "a,b", "a/b", "..", "a..b"] {
+            assert!(BitrateLadder::new(vec![Rendition::new(name, 640, 360, 800_000)]).is_err());
+        }
+        assert!(BitrateLadder::new(vec![
+            Rendition::new("a", 640, 360, 800_000).with_codecs("avc1\"")
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_for_source_height() {
+        let ladder = BitrateLadder::default();
+        let kept = ladder.for_source_height(720);
+        assert_eq!(kept.len(), 3);
+        assert_eq!(kept[0].name, "720p");
+        assert_eq!(ladder.for_source_height(100).len(), 1);
+    }
+}
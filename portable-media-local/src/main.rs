@@ -1,6 +1,177 @@
-
+mod artifacts;
+mod feed;
 mod file_map;
+mod grouping;
+mod hash;
+mod importer;
+mod ladder;
 mod log;
-fn main() {
-    println!("Hello, world!");
+mod organize;
+mod reader;
+mod renamer;
+mod subtitle;
+mod tags;
+#[cfg(test)]
+mod test_util;
+mod zip;
+
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+use file_map::FileMap;
+use grouping::GroupRule;
+use hash::Algorithm;
+use importer::ImportConfig;
+
+const USAGE: &str = "usage:
+  portable-media-local sha256sums|b3sums <root> [dir] [--per-dir] [--write]
+      Prints SHA256SUMS (or BLAKE3 B3SUMS) manifests for dir (relative to root, defaults
+      to all of root). --per-dir gives every directory its own manifest, --write saves
+      them next to the files
+  portable-media-local import <root> <inbox> [template] [--dry-run] [--undo] [--watch=SECS]
+      Moves tagged files from inbox into root, named by template
+      (default \"{artist}/{album}/{track:02} - {title}.{ext}\").
+      --dry-run only prints the moves, --undo puts the last imports back,
+      --watch keeps checking the inbox every SECS seconds
+  portable-media-local rename <root> <dir> <template> [--apply]
+      Renames the files under dir (relative to root) by template using their tags,
+      taking subtitles, posters and the like along. Only prints what would change
+      unless --apply is given";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("sha256sums") => checksums(&args[1..], Algorithm::Sha256).await,
+        Some("b3sums") => checksums(&args[1..], Algorithm::Blake3).await,
+        Some("import") => import(&args[1..]).await,
+        Some("rename") => rename(&args[1..]).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Splits command arguments into positional arguments and `--flags`
+fn split_flags(args: &[String]) -> (Vec<&str>, Vec<&str>) {
+    args.iter()
+        .map(String::as_str)
+        .partition(|a| !a.starts_with("--"))
+}
+
+async fn checksums(args: &[String], algorithm: Algorithm) -> Result<(), Error> {
+    let (positional, flags) = split_flags(args);
+    let (root, dir) = match positional.as_slice() {
+        [root] => (*root, ""),
+        [root, dir] => (*root, *dir),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, USAGE)),
+    };
+    let per_directory = flags.contains(&"--per-dir");
+    let write = flags.contains(&"--write");
+
+    let file_map = FileMap::from_root_dir(root).await?;
+    let manifests = file_map
+        .checksum_manifests(dir, algorithm, per_directory)
+        .await?;
+    for (path, contents) in &manifests {
+        if write {
+            std::fs::write(format!("{}/{}", root, path), contents)?;
+            println!("wrote {}/{}", root, path);
+        } else if manifests.len() > 1 {
+            println!("==> {} <==\n{}", path, contents);
+        } else {
+            print!("{}", contents);
+        }
+    }
+    Ok(())
+}
+
+async fn import(args: &[String]) -> Result<(), Error> {
+    let (positional, flags) = split_flags(args);
+    let (root, mut config) = match positional.as_slice() {
+        [root, inbox] => (*root, ImportConfig::new(inbox)),
+        [root, inbox, template] => (
+            *root,
+            ImportConfig {
+                template: template.to_string(),
+                ..ImportConfig::new(inbox)
+            },
+        ),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, USAGE)),
+    };
+    let file_map = FileMap::from_root_dir(root).await?;
+
+    if flags.contains(&"--undo") {
+        let outcome = importer::undo_inbox(&file_map, &config).await?;
+        for m in &outcome.undone {
+            println!("{} -> {}", m.to, m.from);
+        }
+        if !outcome.remaining.moves.is_empty() {
+            println!(
+                "{} files could not be put back, run --undo again to retry",
+                outcome.remaining.moves.len()
+            );
+        }
+        return Ok(());
+    }
+    if flags.contains(&"--dry-run") {
+        // Nothing gets moved, so don't hold back files that are still settling
+        config.settle = Duration::ZERO;
+        let plan = importer::plan_import(&file_map, &config).await?;
+        for m in &plan.moves {
+            println!("{} -> {}", m.from, m.to);
+        }
+        for (path, reason) in &plan.skipped {
+            println!("skipping {}: {}", path, reason);
+        }
+        return Ok(());
+    }
+    if let Some(secs) = flags.iter().find_map(|f| f.strip_prefix("--watch=")) {
+        let secs: u64 = secs
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, USAGE))?;
+        let watcher =
+            importer::watch_inbox(Arc::new(file_map), config, Duration::from_secs(secs));
+        return watcher.await.map_err(Error::other);
+    }
+
+    let journal = importer::import_inbox(&file_map, &config).await?;
+    for m in &journal.moves {
+        println!("{} -> {}", m.from, m.to);
+    }
+    Ok(())
+}
+
+async fn rename(args: &[String]) -> Result<(), Error> {
+    let (positional, flags) = split_flags(args);
+    let [root, dir, template] = positional.as_slice() else {
+        return Err(Error::new(ErrorKind::InvalidInput, USAGE));
+    };
+    let file_map = FileMap::from_root_dir(root).await?;
+    let plan =
+        renamer::plan_renames(&file_map, dir, template, &GroupRule::defaults()).await?;
+    if !flags.contains(&"--apply") {
+        for r in &plan.renames {
+            println!("{} -> {}", r.from, r.to);
+        }
+        for (path, reason) in &plan.skipped {
+            println!("skipping {}: {}", path, reason);
+        }
+        return Ok(());
+    }
+    for r in renamer::apply_renames(&file_map, &plan).await {
+        println!("{} -> {}", r.from, r.to);
+    }
+    Ok(())
 }
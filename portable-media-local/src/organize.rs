@@ -0,0 +1,157 @@
+use std::io::{Error, ErrorKind};
+
+use crate::tags::Tags;
+
+/// Where music ends up when no template is given
+pub const DEFAULT_TEMPLATE: &str = "{artist}/{album}/{track:02} - {title}.{ext}";
+
+/// Makes a tag value safe to use inside a single path component
+fn sanitize(value: &str) -> String {
+    let cleaned: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    cleaned.trim().to_string()
+}
+
+/// Adds " (2)", " (3)", ... before the extension until `taken` says the name is free
+pub async fn free_name<F, Fut>(path: &str, taken: F) -> String
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    if !taken(path.to_string()).await {
+        return path.to_string();
+    }
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), path),
+    };
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut n = 2;
+    loop {
+        let candidate = format!("{}{} ({}){}", dir, stem, n, ext);
+        if !taken(candidate.clone()).await {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Renders a naming template like `{artist}/{album}/{track:02} - {title}.{ext}` for a file
+/// Available fields are `artist`, `album`, `title`, `track`, `year` from the tags, and
+/// `stem` and `ext` from `file_name`. A `:NN` suffix zero pads a field to NN characters
+/// Returns an `io::Error` if the template uses a tag the file doesn't have, so badly
+/// tagged files get left alone rather than filed under a made up name
+pub fn render_template(template: &str, tags: &Tags, file_name: &str) -> Result<String, Error> {
+    let (stem, ext) = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+        _ => (file_name, ""),
+    };
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let Some(close) = rest[open..].find('}') else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Error: unclosed {{ in template {}", template),
+            ));
+        };
+        let spec = &rest[open + 1..open + close];
+        rest = &rest[open + close + 1..];
+
+        let (field, width) = match spec.split_once(':') {
+            Some((field, width)) => (field, width.parse::<usize>().ok()),
+            None => (spec, None),
+        };
+        let value = match field {
+            "artist" => tags.artist.clone(),
+            "album" => tags.album.clone(),
+            "title" => tags.title.clone(),
+            "track" => tags.track.map(|t| t.to_string()),
+            "year" => tags.year.map(|y| y.to_string()),
+            "stem" => Some(stem.to_string()),
+            "ext" => Some(ext.to_lowercase()),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Error: unknown field {{{}}} in template {}",
+                        field, template
+                    ),
+                ))
+            }
+        };
+        let Some(value) = value.map(|v| sanitize(&v)).filter(|v| !v.is_empty()) else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Error: {} has no {} tag", file_name, field),
+            ));
+        };
+        match width {
+            Some(w) => out.push_str(&format!("{:0>w$}", value, w = w)),
+            None => out.push_str(&value),
+        }
+    }
+    out.push_str(rest);
+
+    // Components can still come out empty or as dots from the literal parts of the template
+    let components: Vec<&str> = out
+        .split('/')
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .collect();
+    if components.is_empty() || components.iter().any(|c| *c == "." || *c == "..") {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Error: template {} gives an invalid path for {}",
+                template, file_name
+            ),
+        ));
+    }
+    Ok(components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> Tags {
+        Tags {
+            title: Some("Song: Part 1/2".to_string()),
+            artist: Some("Band".to_string()),
+            album: Some("Album".to_string()),
+            track: Some(3),
+            year: None,
+        }
+    }
+
+    #[test]
+    fn test_render_template() {
+        assert_eq!(
+            render_template(DEFAULT_TEMPLATE, &tags(), "x.FLAC").unwrap(),
+            "Band/Album/03 - Song_ Part 1_2.flac"
+        );
+        assert_eq!(
+            render_template("{stem}/{stem}.{ext}", &Tags::default(), "a.b.mp3").unwrap(),
+            "a.b/a.b.mp3"
+        );
+    }
+
+    #[test]
+    fn test_render_errors() {
+        assert!(render_template("{year}/{title}", &tags(), "x.mp3").is_err());
+        assert!(render_template("{nope}", &tags(), "x.mp3").is_err());
+        assert!(render_template("{title", &tags(), "x.mp3").is_err());
+        assert!(render_template("../{title}", &tags(), "x.mp3").is_err());
+    }
+}
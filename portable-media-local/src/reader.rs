@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, IoSliceMut, Read};
+
+/// How much is read from disk at a time unless told otherwise
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Chunk sizes are kept to a multiple of this, SD cards and most filesystems
+/// are a lot happier with reads that line up with their blocks
+/// Only read offsets and lengths line up, files aren't opened with `O_DIRECT`,
+/// so the buffers themselves don't need to be aligned in memory
+pub const ALIGNMENT: usize = 4096;
+
+/// How many chunks a single vectored read fills at most
+pub const CHUNKS_PER_READ: usize = 4;
+
+/// Rounds `size` up to a whole number of blocks
+pub fn aligned_chunk_size(size: usize) -> usize {
+    size.max(1).next_multiple_of(ALIGNMENT)
+}
+
+/// How big each buffer should be, and how many of them, to read a file of `size`
+/// bytes `chunk_size` at a time (0 counts as 1). Small files get just the one
+/// buffer, cut down to the blocks they actually need
+pub fn buffers_for(size: u64, chunk_size: usize) -> (usize, usize) {
+    let mut chunk_size = chunk_size.max(1);
+    if size < chunk_size as u64 {
+        chunk_size = chunk_size.min(aligned_chunk_size(size as usize + 1));
+    }
+    let count = (size / chunk_size as u64 + 1).min(CHUNKS_PER_READ as u64) as usize;
+    (chunk_size, count)
+}
+
+/// Fills `bufs` in order from `file` with vectored reads, returning how many bytes
+/// went in, which is less than all of them only at the end of the file
+/// Short reads are topped up, so when every buffer is the same size the next fill
+/// starts at a multiple of it
+pub fn fill<B: AsMut<[u8]>>(file: &mut File, bufs: &mut [B]) -> Result<usize, Error> {
+    let total: usize = bufs.iter_mut().map(|b| b.as_mut().len()).sum();
+    let mut filled = 0;
+    while filled < total {
+        let mut skip = filled;
+        let mut slices = Vec::with_capacity(bufs.len());
+        for buf in bufs.iter_mut() {
+            let buf = buf.as_mut();
+            if skip >= buf.len() {
+                skip -= buf.len();
+                continue;
+            }
+            slices.push(IoSliceMut::new(&mut buf[skip..]));
+            skip = 0;
+        }
+        match file.read_vectored(&mut slices) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads the whole file at `path` on a blocking thread, handing it to `consume` one
+/// `chunk_size` piece at a time, in order, along with `state` (which is handed back)
+/// Pieces are read several at a time with `fill`, so every read starts at a multiple
+/// of `chunk_size` into the file
+pub async fn read_chunks<S, F>(
+    path: String,
+    chunk_size: usize,
+    mut state: S,
+    mut consume: F,
+) -> Result<S, Error>
+where
+    S: Send + 'static,
+    F: FnMut(&mut S, &[u8]) + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut file = File::open(&path)?;
+        let (chunk_size, count) = buffers_for(file.metadata()?.len(), chunk_size);
+        let mut bufs = vec![vec![0u8; chunk_size]; count];
+        loop {
+            let filled = fill(&mut file, &mut bufs)?;
+            for (i, buf) in bufs.iter().enumerate() {
+                let start = i * chunk_size;
+                if start >= filled {
+                    break;
+                }
+                consume(&mut state, &buf[..(filled - start).min(chunk_size)]);
+            }
+            if filled < chunk_size * count {
+                return Ok(state);
+            }
+        }
+    })
+    .await
+    .map_err(Error::other)?
+}
+
+/// Reads the whole file at `path` on a blocking thread, straight into a `Vec` sized
+/// for it, `chunk_size` (at least 1) at a time. Each piece is read in full before
+/// the next starts, so every read starts at a multiple of `chunk_size` into the file
+pub async fn read_to_vec(path: String, chunk_size: usize) -> Result<Vec<u8>, Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = File::open(&path)?;
+        let chunk_size = chunk_size.max(1) as u64;
+        let mut buf = Vec::with_capacity(file.metadata()?.len() as usize);
+        // The file may also have grown since it was sized up, which just means more pieces
+        loop {
+            let read = (&mut file).take(chunk_size).read_to_end(&mut buf)?;
+            if (read as u64) < chunk_size {
+                return Ok(buf);
+            }
+        }
+    })
+    .await
+    .map_err(Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_chunks() {
+        // 13 bytes, 4 at a time, the last chunk is whatever is left
+        let pieces = read_chunks(
+            "../test_dir/testfile1.txt".to_string(),
+            4,
+            Vec::new(),
+            |pieces: &mut Vec<Vec<u8>>, chunk| pieces.push(chunk.to_vec()),
+        )
+        .await
+        .unwrap();
+        let lens: Vec<usize> = pieces.iter().map(|p| p.len()).collect();
+        assert_eq!(lens, vec![4, 4, 4, 1]);
+        assert_eq!(pieces.concat(), b"this is 13 b!");
+        // A chunk size of 0 reads a byte at a time rather than dividing by zero
+        let pieces = read_chunks(
+            "../test_dir/testfile1.txt".to_string(),
+            0,
+            0,
+            |n: &mut usize, _| *n += 1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(pieces, 13);
+
+        let empty = read_chunks(
+            "../test_dir/testfile2.mp4".to_string(),
+            4096,
+            0,
+            |n: &mut usize, chunk| *n += chunk.len(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(empty, 0);
+        assert!(
+            read_chunks("../test_dir/nope".to_string(), 4, (), |_, _| {})
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_to_vec() {
+        let path = "../test_dir/testfile1.txt".to_string();
+        for chunk_size in [0, 4, 13, 4096] {
+            let buf = read_to_vec(path.clone(), chunk_size).await.unwrap();
+            assert_eq!(buf, b"this is 13 b!");
+        }
+    }
+
+    #[test]
+    fn test_buffers_for() {
+        assert_eq!(buffers_for(13, DEFAULT_CHUNK_SIZE), (4096, 1));
+        assert_eq!(buffers_for(13, 4), (4, 4));
+        assert_eq!(buffers_for(13, 0), (1, 4));
+        assert_eq!(
+            buffers_for(1 << 30, DEFAULT_CHUNK_SIZE),
+            (DEFAULT_CHUNK_SIZE, 4)
+        );
+        assert_eq!(
+            buffers_for(u64::MAX, DEFAULT_CHUNK_SIZE),
+            (DEFAULT_CHUNK_SIZE, 4)
+        );
+    }
+
+    #[test]
+    fn test_aligned_chunk_size() {
+        assert_eq!(aligned_chunk_size(0), 4096);
+        assert_eq!(aligned_chunk_size(5000), 8192);
+        assert_eq!(aligned_chunk_size(DEFAULT_CHUNK_SIZE), DEFAULT_CHUNK_SIZE);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Error;
+
+use tokio::fs;
+
+use crate::file_map::FileMap;
+use crate::grouping::{GroupRule, MediaGroup};
+use crate::log::{self, log_err};
+use crate::organize;
+use crate::tags;
+
+/// A file being renamed, both paths relative to the root of the map
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+/// The preview of a batch rename
+/// `skipped` holds files that keep their name, and why
+#[derive(Debug, Default)]
+pub struct RenamePlan {
+    pub renames: Vec<Rename>,
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Finds a free name for `from` to be renamed to, starting with `to`
+async fn free_target(map: &FileMap, planned: &[Rename], from: &str, to: &str) -> String {
+    organize::free_name(to, |candidate| async move {
+        // A name stays taken even if the file there is being renamed away,
+        // so the order renames are applied in never matters
+        candidate != from
+            && (planned.iter().any(|r| r.to == candidate)
+                || map.contains(&candidate).await
+                || fs::try_exists(map.disk_path(&candidate))
+                    .await
+                    .unwrap_or(true))
+    })
+    .await
+}
+
+fn split_name(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some((dir, name)) => (dir, name),
+        None => ("", path),
+    }
+}
+
+fn stem(name: &str) -> &str {
+    match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    }
+}
+
+/// Where a companion file goes when its primary is renamed from `from` to `to`:
+/// next to the primary, with the primary's new stem swapped in for the old one
+/// (so "Movie.en.srt" follows "Movie.mkv" to "New.en.srt")
+fn companion_target(companion: &str, from: &str, to: &str) -> String {
+    let name = split_name(companion).1;
+    let old_stem = stem(split_name(from).1);
+    let (to_dir, to_name) = split_name(to);
+    let new_name = match name.get(..old_stem.len()) {
+        Some(prefix) if prefix.to_lowercase() == old_stem.to_lowercase() => {
+            format!("{}{}", stem(to_name), &name[old_stem.len()..])
+        }
+        _ => name.to_string(),
+    };
+    if to_dir.is_empty() {
+        new_name
+    } else {
+        format!("{}/{}", to_dir, new_name)
+    }
+}
+
+/// Works out the new name of every file under `dir` from its tags and `template`,
+/// without renaming anything. The rendered template is relative to `dir`
+/// When two files would end up with the same name, or the name is already taken,
+/// the later one gets a " (2)" style suffix instead of overwriting anything
+/// Companion files (subtitles, posters, etc. grouped by `rules`) follow their primary
+/// file, keeping whatever comes after its stem, and stay put when it does
+pub async fn plan_renames(
+    map: &FileMap,
+    dir: &str,
+    template: &str,
+    rules: &[GroupRule],
+) -> Result<RenamePlan, Error> {
+    let dir = dir.trim_matches('/');
+    let mut plan = RenamePlan::default();
+    let files: Vec<String> = map
+        .walk(dir)
+        .await?
+        .into_iter()
+        .filter(|(_, node)| node.children.is_none())
+        .map(|(rel_path, _)| rel_path)
+        .collect();
+    // Grouping is the slow part, so each directory is only grouped once, on the way in
+    let mut grouped_dirs: HashSet<String> = HashSet::new();
+    let mut groups: HashMap<String, MediaGroup> = HashMap::new();
+    for rel_path in files {
+        let from = if dir.is_empty() {
+            rel_path.clone()
+        } else {
+            format!("{}/{}", dir, rel_path)
+        };
+        let parent = from.rsplit_once('/').map_or("", |(parent, _)| parent);
+        if grouped_dirs.insert(parent.to_string()) {
+            for group in map.list_grouped(parent, rules).await? {
+                groups.insert(group.primary.clone(), group);
+            }
+        }
+        // Companions aren't the primary of any group, they go along with theirs
+        let Some(group) = groups.remove(&from) else {
+            continue;
+        };
+        let file_tags = match tags::read_tags(&map.disk_path(&from)).await {
+            Ok(t) => t,
+            Err(e) => {
+                plan.skipped.push((from, e.to_string()));
+                continue;
+            }
+        };
+        let file_name = rel_path.rsplit('/').next().unwrap_or(&rel_path);
+        let rendered = match organize::render_template(template, &file_tags, file_name) {
+            Ok(r) => r,
+            Err(e) => {
+                plan.skipped.push((from, e.to_string()));
+                continue;
+            }
+        };
+        let to = if dir.is_empty() {
+            rendered
+        } else {
+            format!("{}/{}", dir, rendered)
+        };
+        let to = free_target(map, &plan.renames, &from, &to).await;
+        if to == from {
+            plan.skipped
+                .push((from, "already named by the template".to_string()));
+            continue;
+        }
+        plan.renames.push(Rename {
+            from: from.clone(),
+            to: to.clone(),
+        });
+        for companion in group.companions {
+            let target = companion_target(&companion, &from, &to);
+            let target = free_target(map, &plan.renames, &companion, &target).await;
+            if target != companion {
+                plan.renames.push(Rename {
+                    from: companion,
+                    to: target,
+                });
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// Carries out a rename plan through the map, so cached contents and hashes follow
+/// the files. Directories left empty by the renames are removed
+/// A rename that fails is logged and skipped, the returned list is what actually happened
+pub async fn apply_renames(map: &FileMap, plan: &RenamePlan) -> Vec<Rename> {
+    let mut done = Vec::new();
+    for r in &plan.renames {
+        if let Err(e) = map.rename_path(&r.from, &r.to).await {
+            log_err(
+                format!("Error renaming {} to {}: {}", r.from, r.to, e).as_str(),
+                log::LogPriority::Middle,
+            );
+            continue;
+        }
+        done.push(r.clone());
+
+        let mut dir = r.from.as_str();
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            let empty = match map.list_dir(parent).await {
+                Ok(entries) => entries.is_empty(),
+                Err(_) => false,
+            };
+            if !empty || fs::remove_dir(map.disk_path(parent)).await.is_err() {
+                break;
+            }
+            let _ = map.remove_path(parent).await;
+            dir = parent;
+        }
+    }
+    done
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Algorithm;
+    use crate::test_util::TempDir;
+
+    async fn setup(name: &str) -> (TempDir, String) {
+        let root = TempDir::new(&format!("rename-{}", name));
+        fs::create_dir_all(root.join("music/old")).await.unwrap();
+        for name in ["music/old/a.mp3", "music/old/b.mp3"] {
+            fs::copy("../test_dir/test2/episode.mp3", root.join(name))
+                .await
+                .unwrap();
+        }
+        fs::write(root.join("music/readme.txt"), "hi")
+            .await
+            .unwrap();
+        let path = root.as_str().to_string();
+        (root, path)
+    }
+
+    #[tokio::test]
+    async fn test_preview_and_collisions() {
+        let (_dir, root) = setup("preview").await;
+        let map = FileMap::from_root_dir(&root).await.unwrap();
+        let plan = plan_renames(
+            &map,
+            "music",
+            "{artist}/{track:02} {title}.{ext}",
+            &GroupRule::defaults(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            plan.renames,
+            vec![
+                Rename {
+                    from: "music/old/a.mp3".to_string(),
+                    to: "music/Host/01 Pilot & Friends.mp3".to_string(),
+                },
+                Rename {
+                    from: "music/old/b.mp3".to_string(),
+                    to: "music/Host/01 Pilot & Friends (2).mp3".to_string(),
+                },
+            ]
+        );
+        assert_eq!(plan.skipped.len(), 1);
+        // Previewing doesn't touch anything
+        assert!(map.contains("music/old/a.mp3").await);
+    }
+
+    #[tokio::test]
+    async fn test_apply_renames() {
+        let (_dir, root) = setup("apply").await;
+        let map = FileMap::from_root_dir(&root).await.unwrap();
+        let before = map
+            .file_hash("music/old/a.mp3", Algorithm::Sha256)
+            .await
+            .unwrap();
+        let plan = plan_renames(&map, "music", "{title}.{ext}", &GroupRule::defaults())
+            .await
+            .unwrap();
+        let done = apply_renames(&map, &plan).await;
+        assert_eq!(done.len(), 2);
+        assert!(map.contains("music/Pilot & Friends.mp3").await);
+        assert!(map.contains("music/Pilot & Friends (2).mp3").await);
+        assert_eq!(
+            map.file_hash("music/Pilot & Friends.mp3", Algorithm::Sha256)
+                .await
+                .unwrap(),
+            before
+        );
+        // The emptied out directory is gone, from the map and from disk
+        assert!(!map.contains("music/old").await);
+        assert!(!fs::try_exists(map.disk_path("music/old")).await.unwrap());
+        assert!(map.contains("music/readme.txt").await);
+
+        // Running it again leaves everything where it is
+        let again = plan_renames(&map, "music", "{title}.{ext}", &GroupRule::defaults())
+            .await
+            .unwrap();
+        assert!(again.renames.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_companions_follow() {
+        let (_dir, root) = setup("companions").await;
+        fs::write(root.clone() + "/music/old/a.en.srt", "subs")
+            .await
+            .unwrap();
+        fs::write(root.clone() + "/music/old/a-poster.jpg", "poster")
+            .await
+            .unwrap();
+        let map = FileMap::from_root_dir(&root).await.unwrap();
+        let rules = vec![GroupRule::new(
+            &["mp3"],
+            &["{stem}.*.srt", "{stem}-poster.jpg"],
+        )];
+        let plan = plan_renames(&map, "music", "{title}.{ext}", &rules)
+            .await
+            .unwrap();
+        let targets: Vec<&str> = plan.renames.iter().map(|r| r.to.as_str()).collect();
+        assert_eq!(
+            targets,
+            vec![
+                "music/Pilot & Friends.mp3",
+                "music/Pilot & Friends-poster.jpg",
+                "music/Pilot & Friends.en.srt",
+                "music/Pilot & Friends (2).mp3",
+            ]
+        );
+
+        apply_renames(&map, &plan).await;
+        let groups = map.list_grouped("music", &rules).await.unwrap();
+        let group = groups
+            .iter()
+            .find(|g| g.primary == "music/Pilot & Friends.mp3")
+            .unwrap();
+        assert_eq!(group.companions.len(), 2);
+    }
+}
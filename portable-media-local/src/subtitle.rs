@@ -0,0 +1,193 @@
+use std::io::{Error, ErrorKind};
+
+/// Windows-1252 mappings for 0x80..=0x9F, the range where it differs from Latin-1
+/// Undefined code points map to U+FFFD
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{FFFD}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{FFFD}', '\u{017D}', '\u{FFFD}',
+    '\u{FFFD}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{FFFD}', '\u{017E}', '\u{0178}',
+];
+
+/// Decodes raw subtitle bytes into a `String`
+/// Checks for a UTF-8 or UTF-16 byte order mark first, then tries plain UTF-8,
+/// and falls back to Windows-1252 since that's what most older SRT files are saved as
+pub fn decode_subtitle(bytes: &[u8]) -> String {
+    if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return String::from_utf8_lossy(rest).into_owned();
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        return decode_utf16(rest, u16::from_le_bytes);
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        return decode_utf16(rest, u16::from_be_bytes);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => bytes
+            .iter()
+            .map(|&b| match b {
+                0x80..=0x9F => CP1252_HIGH[(b - 0x80) as usize],
+                _ => b as char,
+            })
+            .collect(),
+    }
+}
+
+fn decode_utf16(bytes: &[u8], to_unit: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| to_unit([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Parses a subtitle timestamp into milliseconds
+/// Accepts both `HH:MM:SS,mmm` (SRT) and `[HH:]MM:SS.mmm` (WebVTT)
+fn parse_timestamp(stamp: &str) -> Option<u64> {
+    let (clock, millis) = stamp.trim().split_once([',', '.'])?;
+    let parts: Vec<&str> = clock.split(':').collect();
+    let (h, m, s) = match parts.len() {
+        2 => ("0", parts[0], parts[1]),
+        3 => (parts[0], parts[1], parts[2]),
+        _ => return None,
+    };
+    let h: u64 = h.parse().ok()?;
+    let m: u64 = m.parse().ok()?;
+    let s: u64 = s.parse().ok()?;
+    // Some tools write fewer than 3 digits of milliseconds, treat them as a decimal fraction
+    let frac = format!("{:0<3}", millis.get(..3.min(millis.len()))?);
+    let ms: u64 = frac.parse().ok()?;
+    Some(((h * 60 + m) * 60 + s) * 1000 + ms)
+}
+
+fn format_timestamp(ms: u64, separator: char) -> String {
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        ms / 3_600_000,
+        (ms / 60_000) % 60,
+        (ms / 1000) % 60,
+        separator,
+        ms % 1000
+    )
+}
+
+/// Parses a `start --> end [settings]` line into start and end milliseconds
+fn parse_timing(line: &str) -> Option<(u64, u64)> {
+    let (start, rest) = line.split_once("-->")?;
+    let end = rest.split_whitespace().next()?;
+    Some((parse_timestamp(start)?, parse_timestamp(end)?))
+}
+
+/// A cue with its timing and text lines, independent of subtitle format
+struct Cue<'a> {
+    start: u64,
+    end: u64,
+    text: Vec<&'a str>,
+}
+
+/// Splits subtitle text into cues, skipping any block without a timing line
+/// (WebVTT headers, NOTE/STYLE blocks, and the odd garbage block in hand-edited SRTs)
+fn parse_cues(text: &str) -> Vec<Cue<'_>> {
+    let mut cues = Vec::new();
+    let mut lines = text.lines().map(|l| l.trim_end_matches('\r')).peekable();
+    while lines.peek().is_some() {
+        let block: Vec<&str> = lines
+            .by_ref()
+            .take_while(|l| !l.trim().is_empty())
+            .collect();
+        if let Some(timing_at) = block.iter().position(|l| l.contains("-->")) {
+            if let Some((start, end)) = parse_timing(block[timing_at]) {
+                cues.push(Cue {
+                    start,
+                    end,
+                    text: block[timing_at + 1..].to_vec(),
+                });
+            }
+        }
+    }
+    cues
+}
+
+/// Converts SRT subtitle text to WebVTT
+pub fn srt_to_vtt(srt: &str) -> String {
+    let mut out = String::from("WEBVTT\n");
+    for cue in parse_cues(srt) {
+        out.push_str(&format!(
+            "\n{} --> {}\n",
+            format_timestamp(cue.start, '.'),
+            format_timestamp(cue.end, '.')
+        ));
+        for line in cue.text {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Converts WebVTT subtitle text to SRT
+/// Cue settings and identifiers are dropped since SRT has nowhere to put them
+pub fn vtt_to_srt(vtt: &str) -> Result<String, Error> {
+    if !vtt.trim_start().starts_with("WEBVTT") {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Error: subtitle file is missing the WEBVTT header",
+        ));
+    }
+    let mut out = String::new();
+    for (i, cue) in parse_cues(vtt).into_iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "{}\n{} --> {}\n",
+            i + 1,
+            format_timestamp(cue.start, ','),
+            format_timestamp(cue.end, ',')
+        ));
+        for line in cue.text {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRT: &str = "1\r\n00:00:01,000 --> 00:00:02,500\r\nHello\r\nthere\r\n\r\n2\r\n00:01:00,25 --> 00:01:02,000\r\nSecond\r\n";
+
+    #[test]
+    fn test_srt_to_vtt() {
+        let vtt = srt_to_vtt(SRT);
+        assert_eq!(
+            vtt,
+            "WEBVTT\n\n00:00:01.000 --> 00:00:02.500\nHello\nthere\n\n00:01:00.250 --> 00:01:02.000\nSecond\n"
+        );
+    }
+
+    #[test]
+    fn test_vtt_to_srt() {
+        let vtt =
+            "WEBVTT - test\n\nNOTE skip me\n\nintro\n01:02.000 --> 01:03.500 align:start\nHi\n";
+        assert_eq!(
+            vtt_to_srt(vtt).unwrap(),
+            "1\n00:01:02,000 --> 00:01:03,500\nHi\n"
+        );
+        assert!(vtt_to_srt(SRT).is_err());
+    }
+
+    #[test]
+    fn test_decode_subtitle() {
+        assert_eq!(decode_subtitle(b"\xEF\xBB\xBFcaf\xC3\xA9"), "café");
+        assert_eq!(
+            decode_subtitle(b"caf\xE9 \x93hi\x94"),
+            "café \u{201C}hi\u{201D}"
+        );
+        assert_eq!(decode_subtitle(&[0xFF, 0xFE, b'h', 0, b'i', 0]), "hi");
+        assert_eq!(decode_subtitle(&[0xFE, 0xFF, 0, b'h', 0, b'i']), "hi");
+    }
+}
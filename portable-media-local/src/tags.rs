@@ -0,0 +1,229 @@
+use std::io::{Error, ErrorKind, SeekFrom};
+
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// ID3 tags bigger than this are almost certainly corrupt, don't try to allocate for them
+const MAX_TAG_SIZE: usize = 16 * 1024 * 1024;
+
+/// The tag fields we care about, pulled from ID3v2 (MP3) or Vorbis comments (FLAC)
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub year: Option<u32>,
+}
+
+impl Tags {
+    fn set(&mut self, key: &str, value: &str) {
+        let value = value.trim_matches(char::from(0)).trim();
+        if value.is_empty() {
+            return;
+        }
+        // Track and year are often "3/12" and "2004-05-01", only keep the leading number
+        let leading_number = || {
+            value
+                .split(|c: char| !c.is_ascii_digit())
+                .next()
+                .and_then(|n| n.parse().ok())
+        };
+        match key {
+            "TIT2" | "TITLE" => self.title = Some(value.to_string()),
+            "TPE1" | "ARTIST" => self.artist = Some(value.to_string()),
+            "TALB" | "ALBUM" => self.album = Some(value.to_string()),
+            "TRCK" | "TRACKNUMBER" => self.track = leading_number(),
+            "TYER" | "TDRC" | "DATE" => self.year = leading_number(),
+            _ => {}
+        }
+    }
+}
+
+/// Reads the tags of the audio file at `path`
+/// Only the tag itself is read from disk, not the whole file
+/// Files without a supported tag return an empty `Tags`
+pub async fn read_tags(path: &str) -> Result<Tags, Error> {
+    let mut file = File::open(path).await?;
+    let mut header = [0u8; 10];
+    let read = file.read(&mut header).await?;
+    if read >= 10 && &header[..3] == b"ID3" {
+        let size = syncsafe(&header[6..10]);
+        if size > MAX_TAG_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Error: ID3 tag in {} claims to be {} bytes", path, size),
+            ));
+        }
+        let mut tag = vec![0u8; size];
+        file.read_exact(&mut tag).await?;
+        return Ok(parse_id3v2(header[3], header[5], &tag));
+    }
+    if read >= 4 && &header[..4] == b"fLaC" {
+        file.seek(SeekFrom::Start(4)).await?;
+        return read_flac_comments(&mut file).await;
+    }
+    Ok(Tags::default())
+}
+
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |acc, &b| (acc << 7) | (b & 0x7F) as usize)
+}
+
+/// Parses the frames of an ID3v2.3 or v2.4 tag (everything after the 10 byte header)
+fn parse_id3v2(version: u8, flags: u8, tag: &[u8]) -> Tags {
+    let mut tags = Tags::default();
+    if version != 3 && version != 4 {
+        return tags;
+    }
+    let mut pos = 0;
+    // Skip the extended header if there is one
+    if flags & 0x40 != 0 && tag.len() >= 4 {
+        pos = if version == 4 {
+            syncsafe(&tag[..4])
+        } else {
+            u32::from_be_bytes([tag[0], tag[1], tag[2], tag[3]]) as usize + 4
+        };
+    }
+    while pos + 10 <= tag.len() {
+        let id = &tag[pos..pos + 4];
+        if id[0] == 0 {
+            // Reached the padding
+            break;
+        }
+        let size = if version == 4 {
+            syncsafe(&tag[pos + 4..pos + 8])
+        } else {
+            u32::from_be_bytes([tag[pos + 4], tag[pos + 5], tag[pos + 6], tag[pos + 7]]) as usize
+        };
+        let body_start = pos + 10;
+        let body_end = body_start.saturating_add(size).min(tag.len());
+        if id[0] == b'T' {
+            if let Ok(id) = std::str::from_utf8(id) {
+                tags.set(id, &decode_id3_text(&tag[body_start..body_end]));
+            }
+        }
+        pos = body_end;
+    }
+    tags
+}
+
+/// Decodes the body of an ID3 text frame, whose first byte names the encoding
+fn decode_id3_text(body: &[u8]) -> String {
+    let Some((&encoding, text)) = body.split_first() else {
+        return String::new();
+    };
+    match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let mut big_endian = encoding == 2;
+            let mut text = text;
+            if text.starts_with(&[0xFF, 0xFE]) {
+                text = &text[2..];
+            } else if text.starts_with(&[0xFE, 0xFF]) {
+                big_endian = true;
+                text = &text[2..];
+            }
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|p| {
+                    if big_endian {
+                        u16::from_be_bytes([p[0], p[1]])
+                    } else {
+                        u16::from_le_bytes([p[0], p[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    }
+}
+
+/// Walks the FLAC metadata blocks looking for the Vorbis comment block
+/// `file` must be positioned just after the `fLaC` marker
+async fn read_flac_comments(file: &mut File) -> Result<Tags, Error> {
+    loop {
+        let mut block_header = [0u8; 4];
+        file.read_exact(&mut block_header).await?;
+        let is_last = block_header[0] & 0x80 != 0;
+        let block_type = block_header[0] & 0x7F;
+        let size = u32::from_be_bytes([0, block_header[1], block_header[2], block_header[3]]);
+        if block_type == 4 {
+            let mut block = vec![0u8; size as usize];
+            file.read_exact(&mut block).await?;
+            return Ok(parse_vorbis_comments(&block));
+        }
+        if is_last {
+            return Ok(Tags::default());
+        }
+        file.seek(SeekFrom::Current(size as i64)).await?;
+    }
+}
+
+fn parse_vorbis_comments(block: &[u8]) -> Tags {
+    let mut tags = Tags::default();
+    let read_u32 = |pos: usize| -> Option<usize> {
+        let bytes = block.get(pos..pos + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+    let Some(vendor_len) = read_u32(0) else {
+        return tags;
+    };
+    let mut pos = 4 + vendor_len;
+    let Some(count) = read_u32(pos) else {
+        return tags;
+    };
+    pos += 4;
+    for _ in 0..count {
+        let Some(len) = read_u32(pos) else {
+            break;
+        };
+        pos += 4;
+        let Some(comment) = block.get(pos..pos + len) else {
+            break;
+        };
+        pos += len;
+        if let Some((key, value)) = String::from_utf8_lossy(comment).split_once('=') {
+            tags.set(&key.to_uppercase(), value);
+        }
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_id3_tags() {
+        let tags = read_tags("../test_dir/test2/episode.mp3").await.unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Pilot & Friends"));
+        assert_eq!(tags.artist.as_deref(), Some("Host"));
+        assert_eq!(tags.track, Some(1));
+        assert_eq!(tags.album, None);
+    }
+
+    #[test]
+    fn test_vorbis_comments() {
+        let mut block = Vec::new();
+        block.extend_from_slice(&3u32.to_le_bytes());
+        block.extend_from_slice(b"ref");
+        block.extend_from_slice(&2u32.to_le_bytes());
+        for comment in ["title=Song", "TRACKNUMBER=04/10"] {
+            block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            block.extend_from_slice(comment.as_bytes());
+        }
+        let tags = parse_vorbis_comments(&block);
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.track, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_untagged_file() {
+        let tags = read_tags("../test_dir/testfile1.txt").await.unwrap();
+        assert_eq!(tags, Tags::default());
+    }
+}
@@ -0,0 +1,29 @@
+use std::path::{Path, PathBuf};
+
+/// A scratch directory for tests that touch the filesystem, starting out empty
+/// and removed again once it's dropped, even if the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` has to be unique between tests, since they run at the same time
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("pml-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,704 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::{Compress, Crc, FlushCompress, Status};
+use futures_core::Stream;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+use crate::feed;
+use crate::reader;
+
+/// How many chunks `write_to` gathers up into a single vectored write
+const MAX_WRITE_CHUNKS: usize = 8;
+
+/// Data descriptor follows the file data, and names are UTF-8
+const FLAGS: u16 = 0x0008 | 0x0800;
+const VERSION: u16 = 20;
+/// Version needed to extract an entry with ZIP64 fields
+const VERSION_ZIP64: u16 = 45;
+
+/// Files at least this big get ZIP64 sizes. It's a bit under 4 GiB since deflate
+/// can make incompressible data slightly bigger than it started
+const ZIP64_SIZE_THRESHOLD: u64 = u32::MAX as u64 - (16 << 20);
+
+/// How file data is put into the archive
+/// Almost everything in a media library is already compressed, so `Store` is the default,
+/// `Deflate` is worth it for directories of text, subtitles, and the like
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Store,
+    Deflate,
+}
+
+impl Compression {
+    fn method(self) -> u16 {
+        match self {
+            Compression::Store => 0,
+            Compression::Deflate => 8,
+        }
+    }
+}
+
+/// One file or directory to put in the archive
+/// `name` is the path inside the archive, directories end with a `/`
+/// `size` is the file's size going in, used to decide on ZIP64 before anything is written
+pub struct ZipEntry {
+    pub name: String,
+    pub disk_path: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl ZipEntry {
+    fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/// The file back (unless it ran out) and the chunks read from it
+type ReadAhead = JoinHandle<Result<(Option<std::fs::File>, VecDeque<Bytes>), Error>>;
+
+enum State {
+    LocalHeader,
+    Opening(Pin<Box<dyn Future<Output = Result<std::fs::File, Error>> + Send>>),
+    /// The file, until it runs out, and chunks read from it that haven't gone out yet
+    Data(Option<std::fs::File>, VecDeque<Bytes>),
+    /// A vectored read of the next few chunks, running on a blocking thread
+    Reading(ReadAhead),
+    Descriptor,
+    CentralDirectory,
+    Done,
+}
+
+/// Builds a ZIP archive on the fly, one chunk at a time, without ever holding more
+/// than one vectored read's worth of file data (`reader::CHUNKS_PER_READ` chunks)
+/// in memory. Usable as a `Stream` of chunks, or through
+/// `next_chunk` and `write_to`
+/// Files over 4 GiB, archives over 4 GiB, and archives of more than 65535 entries get
+/// ZIP64 records, so nothing has to be rejected partway through
+pub struct ZipStream {
+    entries: Vec<ZipEntry>,
+    current: usize,
+    state: State,
+    /// Bytes of archive produced so far, which is the offset of the next local header
+    offset: u64,
+    entry_offset: u64,
+    crc: Crc,
+    size: u64,
+    compressed_size: u64,
+    /// Whether the current entry has ZIP64 sizes
+    zip64: bool,
+    /// The compressor for the current entry, if it's being deflated
+    deflate: Option<Compress>,
+    central_directory: BytesMut,
+    chunk_size: usize,
+    compression: Compression,
+}
+
+impl ZipStream {
+    pub fn new(entries: Vec<ZipEntry>) -> ZipStream {
+        ZipStream {
+            entries,
+            current: 0,
+            state: State::LocalHeader,
+            offset: 0,
+            entry_offset: 0,
+            crc: Crc::new(),
+            size: 0,
+            compressed_size: 0,
+            zip64: false,
+            deflate: None,
+            central_directory: BytesMut::new(),
+            chunk_size: reader::DEFAULT_CHUNK_SIZE,
+            compression: Compression::Store,
+        }
+    }
+
+    /// Sets how many bytes of file data go in each chunk, at least 1
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> ZipStream {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sets how files are compressed, directories are always stored
+    pub fn with_compression(mut self, compression: Compression) -> ZipStream {
+        self.compression = compression;
+        self
+    }
+
+    /// Writes the whole archive to `writer`, returning how many bytes were written
+    /// Headers are tiny next to file data, so chunks are gathered up and written with
+    /// vectored writes rather than one small write per header
+    pub async fn write_to<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<u64, Error> {
+        let mut written = 0;
+        let mut pending: Vec<Bytes> = Vec::with_capacity(MAX_WRITE_CHUNKS);
+        loop {
+            let chunk = self.next_chunk().await.transpose()?;
+            let finished = chunk.is_none();
+            if let Some(chunk) = chunk {
+                written += chunk.len() as u64;
+                pending.push(chunk);
+            }
+            let pending_size: usize = pending.iter().map(|c| c.len()).sum();
+            if finished || pending.len() == MAX_WRITE_CHUNKS || pending_size >= self.chunk_size {
+                write_all_vectored(writer, &mut pending).await?;
+            }
+            if finished {
+                writer.flush().await?;
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Returns the next chunk of the archive, or `None` once it's complete
+    /// After an error the stream is finished and returns `None`
+    pub async fn next_chunk(&mut self) -> Option<Result<Bytes, Error>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    fn poll_advance(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, Error>> {
+        loop {
+            match &mut self.state {
+                State::LocalHeader => {
+                    let Some(entry) = self.entries.get(self.current) else {
+                        self.state = State::CentralDirectory;
+                        continue;
+                    };
+                    self.entry_offset = self.offset;
+                    self.crc = Crc::new();
+                    self.size = 0;
+                    self.compressed_size = 0;
+                    if entry.is_dir() {
+                        self.zip64 = false;
+                        self.deflate = None;
+                        let header = local_header(entry, Compression::Store, false);
+                        self.push_central_record();
+                        self.current += 1;
+                        return Poll::Ready(Ok(Some(header)));
+                    }
+                    self.zip64 = entry.size >= ZIP64_SIZE_THRESHOLD;
+                    self.deflate = match self.compression {
+                        Compression::Deflate => {
+                            Some(Compress::new(flate2::Compression::default(), false))
+                        }
+                        Compression::Store => None,
+                    };
+                    let disk_path = entry.disk_path.clone();
+                    self.state = State::Opening(Box::pin(async move {
+                        Ok(tokio::fs::File::open(disk_path).await?.into_std().await)
+                    }));
+                }
+                // The header only goes out once the file has opened, so a missing file
+                // fails the stream without leaving a dangling header behind
+                State::Opening(open) => {
+                    let file = ready!(open.as_mut().poll(cx))?;
+                    self.state = State::Data(Some(file), VecDeque::new());
+                    let compression = if self.deflate.is_some() {
+                        Compression::Deflate
+                    } else {
+                        Compression::Store
+                    };
+                    let entry = &self.entries[self.current];
+                    return Poll::Ready(Ok(Some(local_header(entry, compression, self.zip64))));
+                }
+                State::Reading(read) => {
+                    let (file, chunks) =
+                        ready!(Pin::new(read).poll(cx)).map_err(Error::other)??;
+                    self.state = State::Data(file, chunks);
+                }
+                State::Data(file, chunks) => {
+                    let Some(buf) = chunks.pop_front() else {
+                        if let Some(file) = file.take() {
+                            let size = self.entries[self.current].size;
+                            self.state = State::Reading(read_ahead(file, size, self.chunk_size));
+                            continue;
+                        }
+                        self.state = State::Descriptor;
+                        if let Some(compress) = self.deflate.as_mut() {
+                            let tail = deflate(compress, &[], FlushCompress::Finish)?;
+                            self.compressed_size += tail.len() as u64;
+                            if !tail.is_empty() {
+                                return Poll::Ready(Ok(Some(tail)));
+                            }
+                        }
+                        continue;
+                    };
+                    self.crc.update(&buf);
+                    self.size += buf.len() as u64;
+                    let chunk = match self.deflate.as_mut() {
+                        Some(compress) => deflate(compress, &buf, FlushCompress::None)?,
+                        None => buf,
+                    };
+                    self.compressed_size += chunk.len() as u64;
+                    if !self.zip64 && self.size.max(self.compressed_size) > u32::MAX as u64 {
+                        return Poll::Ready(Err(Error::new(
+                            ErrorKind::FileTooLarge,
+                            format!(
+                                "Error: {} grew past 4 GiB while being zipped",
+                                self.entries[self.current].disk_path
+                            ),
+                        )));
+                    }
+                    // The compressor holds on to small inputs until it has a block's worth
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    return Poll::Ready(Ok(Some(chunk)));
+                }
+                State::Descriptor => {
+                    let mut out = BytesMut::with_capacity(24);
+                    out.put_u32_le(0x08074b50);
+                    out.put_u32_le(self.crc.sum());
+                    if self.zip64 {
+                        out.put_u64_le(self.compressed_size);
+                        out.put_u64_le(self.size);
+                    } else {
+                        out.put_u32_le(self.compressed_size as u32);
+                        out.put_u32_le(self.size as u32);
+                    }
+                    self.push_central_record();
+                    self.current += 1;
+                    self.state = State::LocalHeader;
+                    return Poll::Ready(Ok(Some(out.freeze())));
+                }
+                State::CentralDirectory => {
+                    let mut out = std::mem::take(&mut self.central_directory);
+                    let count = self.entries.len() as u64;
+                    let cd_size = out.len() as u64;
+                    let cd_offset = self.offset;
+                    if count >= u16::MAX as u64
+                        || cd_size >= u32::MAX as u64
+                        || cd_offset >= u32::MAX as u64
+                    {
+                        // ZIP64 end of central directory record, then the locator pointing at it
+                        out.put_u32_le(0x06064b50);
+                        out.put_u64_le(44);
+                        out.put_u16_le((3 << 8) | VERSION_ZIP64);
+                        out.put_u16_le(VERSION_ZIP64);
+                        out.put_u32_le(0);
+                        out.put_u32_le(0);
+                        out.put_u64_le(count);
+                        out.put_u64_le(count);
+                        out.put_u64_le(cd_size);
+                        out.put_u64_le(cd_offset);
+                        out.put_u32_le(0x07064b50);
+                        out.put_u32_le(0);
+                        out.put_u64_le(cd_offset + cd_size);
+                        out.put_u32_le(1);
+                    }
+                    // Anything too big for the classic record is left at its max for ZIP64
+                    out.put_u32_le(0x06054b50);
+                    out.put_u16_le(0);
+                    out.put_u16_le(0);
+                    out.put_u16_le(count.min(u16::MAX as u64) as u16);
+                    out.put_u16_le(count.min(u16::MAX as u64) as u16);
+                    out.put_u32_le(cd_size.min(u32::MAX as u64) as u32);
+                    out.put_u32_le(cd_offset.min(u32::MAX as u64) as u32);
+                    out.put_u16_le(0);
+                    self.state = State::Done;
+                    return Poll::Ready(Ok(Some(out.freeze())));
+                }
+                State::Done => return Poll::Ready(Ok(None)),
+            }
+        }
+    }
+
+    /// Adds the central directory record for the entry that was just written
+    fn push_central_record(&mut self) {
+        let entry = &self.entries[self.current];
+        let (time, date) = dos_time(entry.modified);
+        let external_attrs: u32 = if entry.is_dir() {
+            (0o40755 << 16) | 0x10
+        } else {
+            0o100644 << 16
+        };
+        let method = if self.deflate.is_some() {
+            Compression::Deflate.method()
+        } else {
+            Compression::Store.method()
+        };
+
+        // ZIP64 values go in an extra field, in this order, with the classic fields maxed out
+        let mut extra = BytesMut::new();
+        let (compressed_size, size) = if self.zip64 {
+            extra.put_u64_le(self.size);
+            extra.put_u64_le(self.compressed_size);
+            (u32::MAX, u32::MAX)
+        } else {
+            (self.compressed_size as u32, self.size as u32)
+        };
+        let offset = if self.entry_offset >= u32::MAX as u64 {
+            extra.put_u64_le(self.entry_offset);
+            u32::MAX
+        } else {
+            self.entry_offset as u32
+        };
+        let version = if extra.is_empty() {
+            VERSION
+        } else {
+            VERSION_ZIP64
+        };
+
+        let cd = &mut self.central_directory;
+        cd.put_u32_le(0x02014b50);
+        // Made by unix, so the permission bits in the external attributes are honoured
+        cd.put_u16_le((3 << 8) | version);
+        cd.put_u16_le(version);
+        cd.put_u16_le(if entry.is_dir() { 0x0800 } else { FLAGS });
+        cd.put_u16_le(method);
+        cd.put_u16_le(time);
+        cd.put_u16_le(date);
+        cd.put_u32_le(self.crc.sum());
+        cd.put_u32_le(compressed_size);
+        cd.put_u32_le(size);
+        cd.put_u16_le(entry.name.len() as u16);
+        cd.put_u16_le(if extra.is_empty() {
+            0
+        } else {
+            4 + extra.len() as u16
+        });
+        cd.put_u16_le(0);
+        cd.put_u16_le(0);
+        cd.put_u16_le(0);
+        cd.put_u32_le(external_attrs);
+        cd.put_u32_le(offset);
+        cd.put_slice(entry.name.as_bytes());
+        if !extra.is_empty() {
+            cd.put_u16_le(0x0001);
+            cd.put_u16_le(extra.len() as u16);
+            cd.put_slice(&extra);
+        }
+    }
+}
+
+impl Stream for ZipStream {
+    type Item = Result<Bytes, Error>;
+
+    /// After an error the stream is finished and returns `None`
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        match ready!(this.poll_advance(cx)) {
+            Ok(Some(chunk)) => {
+                this.offset += chunk.len() as u64;
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Ok(None) => Poll::Ready(None),
+            Err(e) => {
+                this.state = State::Done;
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
+}
+
+/// Reads the next few chunks of `file` with one vectored read on a blocking thread
+/// (tokio's files only read into one buffer at a time), handing the file back
+/// unless it ran out. `size` is what the file is expected to hold
+fn read_ahead(mut file: std::fs::File, size: u64, chunk_size: usize) -> ReadAhead {
+    tokio::task::spawn_blocking(move || {
+        let (chunk_size, count) = reader::buffers_for(size, chunk_size);
+        let mut bufs: Vec<BytesMut> = (0..count).map(|_| BytesMut::zeroed(chunk_size)).collect();
+        let mut left = reader::fill(&mut file, &mut bufs)?;
+        let ran_out = left < chunk_size * count;
+        let mut chunks = VecDeque::with_capacity(count);
+        for mut buf in bufs {
+            if left == 0 {
+                break;
+            }
+            buf.truncate(left.min(chunk_size));
+            left -= buf.len();
+            chunks.push_back(buf.freeze());
+        }
+        Ok((if ran_out { None } else { Some(file) }, chunks))
+    })
+}
+
+/// Writes every buffer in `bufs` out with vectored writes, leaving `bufs` empty
+async fn write_all_vectored<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    bufs: &mut Vec<Bytes>,
+) -> Result<(), Error> {
+    let mut start = 0;
+    while start < bufs.len() {
+        let slices: Vec<IoSlice> = bufs[start..].iter().map(|b| IoSlice::new(b)).collect();
+        let mut n = writer.write_vectored(&slices).await?;
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::WriteZero,
+                "Error: failed to write ZIP data",
+            ));
+        }
+        // Skip past whatever was fully written, and trim the one cut off partway
+        while start < bufs.len() && n >= bufs[start].len() {
+            n -= bufs[start].len();
+            start += 1;
+        }
+        if n > 0 {
+            bufs[start].advance(n);
+        }
+    }
+    bufs.clear();
+    Ok(())
+}
+
+/// Runs `input` through `compress`, returning whatever compressed data comes out of it
+/// With `FlushCompress::Finish` this also flushes out the end of the deflate stream
+fn deflate(
+    compress: &mut Compress,
+    mut input: &[u8],
+    flush: FlushCompress,
+) -> Result<Bytes, Error> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 64);
+    loop {
+        if out.capacity() - out.len() < 64 {
+            out.reserve(out.capacity().max(4096));
+        }
+        let before = compress.total_in();
+        let status = compress
+            .compress_vec(input, &mut out, flush)
+            .map_err(|e| Error::other(format!("Error: failed to deflate ZIP data: {}", e)))?;
+        input = &input[(compress.total_in() - before) as usize..];
+        // Anything short of filling the output means it's taken all it can for now
+        let done = match flush {
+            FlushCompress::Finish => status == Status::StreamEnd,
+            _ => input.is_empty() && out.len() < out.capacity(),
+        };
+        if done {
+            return Ok(Bytes::from(out));
+        }
+    }
+}
+
+/// Local file header, with the CRC and sizes left zero for the data descriptor to fill in
+/// ZIP64 entries get maxed out sizes and an (also zero) ZIP64 extra field instead
+fn local_header(entry: &ZipEntry, compression: Compression, zip64: bool) -> Bytes {
+    let (time, date) = dos_time(entry.modified);
+    let mut out = BytesMut::with_capacity(50 + entry.name.len());
+    out.put_u32_le(0x04034b50);
+    out.put_u16_le(if zip64 { VERSION_ZIP64 } else { VERSION });
+    out.put_u16_le(if entry.is_dir() { 0x0800 } else { FLAGS });
+    out.put_u16_le(compression.method());
+    out.put_u16_le(time);
+    out.put_u16_le(date);
+    out.put_u32_le(0);
+    let placeholder_size = if zip64 { u32::MAX } else { 0 };
+    out.put_u32_le(placeholder_size);
+    out.put_u32_le(placeholder_size);
+    out.put_u16_le(entry.name.len() as u16);
+    out.put_u16_le(if zip64 { 20 } else { 0 });
+    out.put_slice(entry.name.as_bytes());
+    if zip64 {
+        out.put_u16_le(0x0001);
+        out.put_u16_le(16);
+        out.put_u64_le(0);
+        out.put_u64_le(0);
+    }
+    out.freeze()
+}
+
+/// Converts a unix timestamp to MS-DOS (time, date), clamping to the 1980 epoch
+fn dos_time(secs: u64) -> (u16, u16) {
+    let (y, mo, d, h, mi, s, _) = feed::civil_from_unix(secs);
+    if y < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = (h << 11) | (mi << 5) | (s / 2);
+    let date = ((y - 1980).min(127) << 9) | (mo << 5) | d;
+    (time as u16, date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    /// Reads a whole archive out through the `Stream` impl
+    async fn collect<S: Stream<Item = Result<Bytes, Error>> + Unpin>(mut stream: S) -> Vec<u8> {
+        let mut archive = Vec::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+        {
+            archive.extend_from_slice(&chunk.unwrap());
+        }
+        archive
+    }
+
+    fn testfile_entry(name: &str) -> ZipEntry {
+        ZipEntry {
+            name: name.to_string(),
+            disk_path: "../test_dir/testfile1.txt".to_string(),
+            size: 13,
+            modified: 0,
+        }
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = Crc::new();
+        crc.update(data);
+        crc.sum()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        // Feeding it in pieces gives the same result
+        let mut crc = Crc::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.sum(), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_dos_time() {
+        assert_eq!(
+            dos_time(1709210096),
+            ((12 << 11) | (34 << 5) | 28, (44 << 9) | (2 << 5) | 29)
+        );
+        assert_eq!(dos_time(0), (0, (1 << 5) | 1));
+    }
+
+    #[tokio::test]
+    async fn test_zip_stream() {
+        let archive = collect(ZipStream::new(vec![
+            ZipEntry {
+                name: "dir/".to_string(),
+                disk_path: String::new(),
+                size: 0,
+                modified: 0,
+            },
+            testfile_entry("dir/testfile1.txt"),
+        ]))
+        .await;
+        assert_eq!(&archive[..4], &[0x50, 0x4b, 0x03, 0x04]);
+        assert!(archive.windows(13).any(|w| w == b"this is 13 b!"));
+        // End of central directory record lists both entries
+        let eocd = &archive[archive.len() - 22..];
+        assert_eq!(&eocd[..4], &[0x50, 0x4b, 0x05, 0x06]);
+        assert_eq!(u16::from_le_bytes([eocd[10], eocd[11]]), 2);
+        let cd_offset = u32::from_le_bytes([eocd[16], eocd[17], eocd[18], eocd[19]]) as usize;
+        assert_eq!(
+            &archive[cd_offset..cd_offset + 4],
+            &[0x50, 0x4b, 0x01, 0x02]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_to() {
+        let entries = || vec![testfile_entry("testfile1.txt")];
+        let mut chunked = Vec::new();
+        let mut stream = ZipStream::new(entries()).with_chunk_size(4);
+        while let Some(chunk) = stream.next_chunk().await {
+            chunked.extend_from_slice(&chunk.unwrap());
+        }
+
+        let mut written = Vec::new();
+        let size = ZipStream::new(entries())
+            .with_chunk_size(4)
+            .write_to(&mut written)
+            .await
+            .unwrap();
+        assert_eq!(size, written.len() as u64);
+        assert_eq!(written, chunked);
+    }
+
+    #[tokio::test]
+    async fn test_zero_chunk_size() {
+        // Rather than every file coming out empty
+        let entries = || vec![testfile_entry("testfile1.txt")];
+        let archive = collect(ZipStream::new(entries()).with_chunk_size(0)).await;
+        assert!(archive.windows(13).any(|w| w == b"this is 13 b!"));
+        assert_eq!(archive, collect(ZipStream::new(entries())).await);
+    }
+
+    #[tokio::test]
+    async fn test_missing_file() {
+        let mut stream = ZipStream::new(vec![ZipEntry {
+            name: "nope".to_string(),
+            disk_path: "../test_dir/nope".to_string(),
+            size: 0,
+            modified: 0,
+        }]);
+        assert!(stream.next_chunk().await.unwrap().is_err());
+        assert!(stream.next_chunk().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deflate() {
+        // Read 4 bytes at a time, so the compressor sees several small inputs
+        let stream = ZipStream::new(vec![testfile_entry("testfile1.txt")])
+            .with_chunk_size(4)
+            .with_compression(Compression::Deflate);
+        let archive = collect(stream).await;
+        assert_eq!(u16::from_le_bytes([archive[8], archive[9]]), 8);
+        let name_len = u16::from_le_bytes([archive[26], archive[27]]) as usize;
+        let data = &archive[30 + name_len..];
+        let mut inflated = Vec::new();
+        flate2::read::DeflateDecoder::new(data)
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, b"this is 13 b!");
+
+        // The data descriptor has the CRC, then the compressed and real sizes
+        let descriptor = archive
+            .windows(4)
+            .position(|w| w == [0x50, 0x4b, 0x07, 0x08]);
+        let d = &archive[descriptor.unwrap()..];
+        assert_eq!(
+            u32::from_le_bytes([d[4], d[5], d[6], d[7]]),
+            crc32(b"this is 13 b!")
+        );
+        assert_eq!(u32::from_le_bytes([d[12], d[13], d[14], d[15]]), 13);
+    }
+
+    #[tokio::test]
+    async fn test_zip64() {
+        // Pretend the file is huge and the archive already past 4 GiB, reading a
+        // real multi GiB file would make the test crawl
+        let mut entry = testfile_entry("big.mkv");
+        entry.size = 5 << 30;
+        let mut stream = ZipStream::new(vec![entry]);
+        stream.offset = 5 << 30;
+        let mut archive = Vec::new();
+        while let Some(chunk) = stream.next_chunk().await {
+            archive.extend_from_slice(&chunk.unwrap());
+        }
+
+        // Local header announces ZIP64, and the data descriptor has 8 byte sizes
+        assert_eq!(u16::from_le_bytes([archive[4], archive[5]]), VERSION_ZIP64);
+        assert_eq!(&archive[30 + 7..30 + 7 + 4], &[0x01, 0x00, 16, 0]);
+        let d = 30 + 7 + 20 + 13;
+        assert_eq!(&archive[d..d + 4], &[0x50, 0x4b, 0x07, 0x08]);
+        assert_eq!(
+            u64::from_le_bytes(archive[d + 16..d + 24].try_into().unwrap()),
+            13
+        );
+
+        // The central directory record puts sizes and offset in its ZIP64 extra field
+        let cd = d + 24;
+        assert_eq!(&archive[cd..cd + 4], &[0x50, 0x4b, 0x01, 0x02]);
+        assert_eq!(&archive[cd + 42..cd + 46], &[0xff; 4]);
+        let extra = &archive[cd + 46 + 7..cd + 46 + 7 + 28];
+        assert_eq!(&extra[..4], &[0x01, 0x00, 24, 0]);
+        assert_eq!(u64::from_le_bytes(extra[4..12].try_into().unwrap()), 13);
+        assert_eq!(
+            u64::from_le_bytes(extra[20..28].try_into().unwrap()),
+            5 << 30
+        );
+
+        // Followed by the ZIP64 end records, then a classic one pointing at them
+        let eocd64 = cd + 46 + 7 + 28;
+        assert_eq!(&archive[eocd64..eocd64 + 4], &[0x50, 0x4b, 0x06, 0x06]);
+        assert_eq!(
+            &archive[eocd64 + 56..eocd64 + 60],
+            &[0x50, 0x4b, 0x06, 0x07]
+        );
+        let eocd = &archive[archive.len() - 22..];
+        assert_eq!(&eocd[..4], &[0x50, 0x4b, 0x05, 0x06]);
+        assert_eq!(&eocd[16..20], &[0xff; 4]);
+    }
+}